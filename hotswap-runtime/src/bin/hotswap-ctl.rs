@@ -0,0 +1,48 @@
+// Sends a command to the control socket of a program using hotswap, e.g.
+// `hotswap-ctl /tmp/game.sock reload`.
+
+use std::process;
+
+#[cfg(unix)]
+fn main() {
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    if args.len() < 2 {
+        eprintln!(
+            "usage: hotswap-ctl <socket> <status|reload|rollback|pin|functions> [generation]"
+        );
+        process::exit(2);
+    }
+
+    let mut stream = UnixStream::connect(&args[0]).unwrap_or_else(|err| {
+        eprintln!("Couldn't connect to {}: {}", args[0], err);
+        process::exit(1);
+    });
+
+    writeln!(stream, "{}", args[1..].join(" ")).expect("Couldn't send the command");
+
+    let mut failed = false;
+    for line in BufReader::new(stream).lines() {
+        let line = line.expect("Couldn't read the response");
+        if line.is_empty() {
+            break;
+        }
+
+        failed |= line.starts_with("error");
+        println!("{}", line);
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("hotswap-ctl is only supported on Unix");
+    process::exit(1);
+}
@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Runtime options, passed as the argument of `hotswap_start!`.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub unload_policy: UnloadPolicy,
+    /// A signal that triggers a reload when received, e.g. `libc::SIGUSR1`.
+    /// Only supported on Unix.
+    pub reload_signal: Option<i32>,
+    /// A Unix domain socket to listen on for control commands, used by the
+    /// `hotswap-ctl` client. Only supported on Unix.
+    pub control_socket: Option<PathBuf>,
+    /// Rolls back to the previous version when the newest one keeps panicking.
+    pub auto_rollback: Option<AutoRollback>,
+    /// Makes the watcher thread only load new versions, which become current
+    /// at the next `hotswap_runtime::safe_point()` call.
+    pub safe_points: bool,
+    /// Makes every swap wait until no hotswapped function is running, blocking
+    /// new calls meanwhile, and abort if that takes longer than the timeout.
+    pub quiescent_swap: Option<Duration>,
+}
+
+/// Rolls back to the previous version once `#[hotswap(catch_panic)]` functions
+/// from the newest version panicked `panics` times within `window`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoRollback {
+    pub panics: usize,
+    pub window: Duration,
+}
+
+/// Decides when a library replaced by a newer version is unloaded.
+///
+/// Unloading is unsound if the library registered thread-local destructors or
+/// atexit handlers, or leaked pointers to its code or data, so libraries are
+/// never unloaded by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnloadPolicy {
+    /// Unload as soon as no function from the library is referenced.
+    WhenUnreferenced,
+    /// Unload once unreferenced and replaced for at least the given duration.
+    GracePeriod(Duration),
+    /// Keep every loaded library until the process exits.
+    Never,
+}
+
+impl Default for UnloadPolicy {
+    fn default() -> Self {
+        UnloadPolicy::Never
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use {ReloadError, ReloadSummary};
+use {functions, pin, reload_now, rollback, rollback_previous, versions};
+
+pub struct Listener {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Listener {
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // Wake up the listener thread, so it notices it was stopped.
+        let _ = UnixStream::connect(&self.path);
+        let _ = self.thread.join();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Listens for commands on a Unix domain socket. Each command is a single line,
+// and each response is a list of lines ended by an empty line, responses to
+// failed commands start with `error`.
+pub fn spawn(path: &Path) -> io::Result<Listener> {
+    // Remove the socket left behind by a previous run of the program.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopped_move = stopped.clone();
+
+    let thread = thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stopped_move.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if let Ok(stream) = stream {
+                thread::spawn(move || handle_client(stream));
+            }
+        }
+    });
+
+    Ok(Listener {
+        path: path.to_path_buf(),
+        stopped: stopped,
+        thread: thread,
+    })
+}
+
+fn handle_client(stream: UnixStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        for response_line in run_command(&line) {
+            writeln!(writer, "{}", response_line)?;
+        }
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+fn run_command(line: &str) -> Vec<String> {
+    let mut args = line.split_whitespace();
+    let command = args.next().unwrap_or("");
+
+    let generation = match args.next() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(generation) => Some(generation),
+            Err(_) => return vec![format!("error invalid generation `{}`", arg)],
+        },
+        None => None,
+    };
+
+    if args.next().is_some() {
+        return vec![format!("error too many arguments in `{}`", line.trim())];
+    }
+
+    match (command, generation) {
+        ("status", None) => versions()
+            .into_iter()
+            .map(|version| {
+                format!(
+                    "{}{}{} refs={} path={}",
+                    version.generation,
+                    if version.current { " current" } else { "" },
+                    if version.pinned { " pinned" } else { "" },
+                    version.live_refs,
+                    version.path.to_string_lossy()
+                )
+            })
+            .collect(),
+        ("reload", None) => vec![summary_line(reload_now())],
+        ("rollback", None) => vec![summary_line(rollback_previous())],
+        ("rollback", Some(generation)) => vec![summary_line(rollback(generation))],
+        ("pin", Some(generation)) => vec![if pin(generation) {
+            "ok".to_string()
+        } else {
+            format!("error version {} is not loaded", generation)
+        }],
+        ("functions", None) => functions().into_iter().map(String::from).collect(),
+        _ => vec![format!("error unknown command `{}`", line.trim())],
+    }
+}
+
+fn summary_line(result: Result<ReloadSummary, ReloadError>) -> String {
+    match result {
+        Ok(summary) => format!("ok {}", summary.generation),
+        Err(err) => format!("error {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_command;
+
+    // The runtime isn't started in the tests, so every command sees no versions.
+
+    #[test]
+    fn lists_nothing_before_start() {
+        assert!(run_command("status").is_empty());
+        assert!(run_command("functions").is_empty());
+    }
+
+    #[test]
+    fn reports_failed_commands() {
+        assert_eq!(run_command("reload"), ["error hotswap_start!() wasn't called"]);
+        assert_eq!(run_command("rollback 1"), ["error hotswap_start!() wasn't called"]);
+        assert_eq!(run_command("pin 3"), ["error version 3 is not loaded"]);
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(run_command("restart"), ["error unknown command `restart`"]);
+        assert_eq!(run_command("pin"), ["error unknown command `pin`"]);
+        assert_eq!(run_command("status 1"), ["error unknown command `status 1`"]);
+        assert_eq!(run_command("rollback abc"), ["error invalid generation `abc`"]);
+        assert_eq!(run_command("pin -1"), ["error invalid generation `-1`"]);
+        assert_eq!(run_command("pin 1 2"), ["error too many arguments in `pin 1 2`"]);
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace() {
+        assert_eq!(run_command("  pin   3 "), ["error version 3 is not loaded"]);
+        assert_eq!(run_command(" restart  "), ["error unknown command `restart`"]);
+    }
+}
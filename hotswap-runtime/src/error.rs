@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// The reasons a new library version can fail to load.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// `hotswap_start!` wasn't called, or the watcher thread has stopped.
+    NotStarted,
+    /// The library couldn't be copied to the `hotswap-dylib` folder.
+    Copy(io::Error),
+    /// The library copy couldn't be loaded.
+    Load(io::Error),
+    /// The library doesn't export one of the hotswapped functions.
+    MissingFunction(&'static str, io::Error),
+    /// The library doesn't export one of the shared statics.
+    SharedStatic(io::Error),
+    /// A `hotswap_on_load` or `hotswap_on_unload` hook failed.
+    Hook(&'static str, String),
+    /// There is no loaded version or library copy with this generation.
+    UnknownVersion(usize),
+    /// There is no version older than the current one to roll back to.
+    NoPreviousVersion,
+    /// The version to roll back to is the current one already.
+    AlreadyCurrent(usize),
+    /// Hotswapped functions were still running when the quiescent swap timed out.
+    NotQuiescent(Duration),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReloadError::NotStarted => write!(f, "hotswap_start!() wasn't called"),
+            ReloadError::Copy(ref err) => write!(
+                f,
+                "Couldn't copy the dynamic library, maybe the destination has too-restrictive \
+                 permissions: {}",
+                err
+            ),
+            ReloadError::Load(ref err) => write!(f, "Failed to load library: {}", err),
+            ReloadError::MissingFunction(name, ref err) => write!(
+                f,
+                "Couldn't find function `{}` on hotswapped library: {}",
+                name, err
+            ),
+            ReloadError::SharedStatic(ref err) => {
+                write!(f, "Couldn't find a shared static on hotswapped library: {}", err)
+            }
+            ReloadError::Hook(name, ref err) => write!(f, "Hook `{}` failed: {}", name, err),
+            ReloadError::UnknownVersion(generation) => {
+                write!(f, "There is no library version {}", generation)
+            }
+            ReloadError::NoPreviousVersion => {
+                write!(f, "There is no library version to roll back to")
+            }
+            ReloadError::AlreadyCurrent(generation) => {
+                write!(f, "Library version {} is the current one already", generation)
+            }
+            ReloadError::NotQuiescent(timeout) => write!(
+                f,
+                "Hotswapped functions were still running after {:?}, the swap was aborted",
+                timeout
+            ),
+        }
+    }
+}
+
+impl Error for ReloadError {
+    fn description(&self) -> &str {
+        match *self {
+            ReloadError::NotStarted => "hotswap not started",
+            ReloadError::Copy(_) => "couldn't copy library",
+            ReloadError::Load(_) => "couldn't load library",
+            ReloadError::MissingFunction(..) => "missing hotswapped function",
+            ReloadError::SharedStatic(_) => "missing shared static",
+            ReloadError::Hook(..) => "hook failed",
+            ReloadError::UnknownVersion(_) => "unknown library version",
+            ReloadError::NoPreviousVersion => "no previous library version",
+            ReloadError::AlreadyCurrent(_) => "library version already current",
+            ReloadError::NotQuiescent(_) => "hotswapped functions still running",
+        }
+    }
+}
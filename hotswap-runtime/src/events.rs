@@ -0,0 +1,146 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use parking_lot::Mutex;
+
+use error::ReloadError;
+use panics::PanicReport;
+use reloader::ReloadSummary;
+
+/// Sent to the `subscribe()` receivers whenever the library is swapped, fails
+/// to be swapped, or panics in a `#[hotswap(catch_panic)]` function.
+#[derive(Clone, Debug)]
+pub enum ReloadEvent {
+    Reloaded(ReloadSummary),
+    RolledBack(ReloadSummary),
+    Failed(String),
+    Panicked(PanicReport),
+}
+
+static SUBSCRIBERS: Mutex<Option<Vec<Sender<ReloadEvent>>>> = Mutex::new(None);
+
+/// Returns a channel receiving an event for every reload, rollback, failed
+/// reload and caught panic from now on.
+pub fn subscribe() -> Receiver<ReloadEvent> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().get_or_insert_with(Vec::new).push(sender);
+    receiver
+}
+
+pub fn emit(event: ReloadEvent) {
+    // Forget the subscribers whose receivers were dropped.
+    if let Some(ref mut subscribers) = *SUBSCRIBERS.lock() {
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+pub fn report(
+    result: &Result<ReloadSummary, ReloadError>,
+    event: fn(ReloadSummary) -> ReloadEvent,
+) {
+    emit(match *result {
+        Ok(ref summary) => event(summary.clone()),
+        Err(ref err) => ReloadEvent::Failed(err.to_string()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+
+    use super::{emit, report, subscribe, ReloadEvent, SUBSCRIBERS};
+    use error::ReloadError;
+    use reloader::ReloadSummary;
+
+    // Other tests emit events as well, but only these ones subscribe.
+    static SUBSCRIBING: Mutex<()> = Mutex::new(());
+
+    fn summary(generation: usize) -> ReloadSummary {
+        ReloadSummary {
+            generation: generation,
+            previous: None,
+            path: PathBuf::new(),
+            functions: Vec::new(),
+            duration: Duration::from_millis(0),
+        }
+    }
+
+    fn subscribers() -> usize {
+        SUBSCRIBERS.lock().as_ref().map_or(0, Vec::len)
+    }
+
+    // The events sent by the given test, told apart by the generation or error.
+    fn received(receiver: &Receiver<ReloadEvent>, generation: usize) -> Vec<ReloadEvent> {
+        let failed = ReloadError::UnknownVersion(generation).to_string();
+        receiver
+            .try_iter()
+            .filter(|event| match *event {
+                ReloadEvent::Reloaded(ref summary) | ReloadEvent::RolledBack(ref summary) => {
+                    summary.generation == generation
+                }
+                ReloadEvent::Failed(ref err) => *err == failed,
+                ReloadEvent::Panicked(_) => false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_subscriber_receives_events() {
+        let _subscribing = SUBSCRIBING.lock();
+        let first = subscribe();
+        let second = subscribe();
+
+        emit(ReloadEvent::Reloaded(summary(36001)));
+
+        for receiver in &[first, second] {
+            let events = received(receiver, 36001);
+            assert_eq!(events.len(), 1);
+            match events[0] {
+                ReloadEvent::Reloaded(ref summary) => assert_eq!(summary.generation, 36001),
+                ref event => panic!("Unexpected event {:?}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn forgets_dropped_subscribers() {
+        let _subscribing = SUBSCRIBING.lock();
+        let kept = subscribe();
+
+        // Forget the subscribers dropped by the other tests first.
+        emit(ReloadEvent::Reloaded(summary(36002)));
+        let before = subscribers();
+
+        drop(subscribe());
+        assert_eq!(subscribers(), before + 1);
+
+        emit(ReloadEvent::Reloaded(summary(36002)));
+        assert_eq!(subscribers(), before);
+        assert_eq!(received(&kept, 36002).len(), 2);
+    }
+
+    #[test]
+    fn reports_results() {
+        let _subscribing = SUBSCRIBING.lock();
+        let receiver = subscribe();
+
+        report(&Ok(summary(36003)), ReloadEvent::RolledBack);
+        report(&Err(ReloadError::UnknownVersion(36003)), ReloadEvent::RolledBack);
+
+        let events = received(&receiver, 36003);
+        assert_eq!(events.len(), 2);
+        match events[0] {
+            ReloadEvent::RolledBack(ref summary) => assert_eq!(summary.generation, 36003),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+        match events[1] {
+            ReloadEvent::Failed(ref err) => {
+                assert_eq!(*err, ReloadError::UnknownVersion(36003).to_string())
+            }
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
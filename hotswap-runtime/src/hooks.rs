@@ -0,0 +1,43 @@
+use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
+
+use version::Version;
+
+/// The return types allowed for the `hotswap_on_load` and `hotswap_on_unload`
+/// hooks, returning an error aborts the swap.
+pub trait HookResult {
+    fn into_hook_result(self) -> Result<(), String>;
+}
+
+impl HookResult for () {
+    fn into_hook_result(self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<E: Display> HookResult for Result<(), E> {
+    fn into_hook_result(self) -> Result<(), String> {
+        self.map_err(|err| err.to_string())
+    }
+}
+
+// Calls the hooks exported by a version, stopping at the first one that fails.
+pub fn run(version: &Version, hooks: &[&'static str]) -> Result<(), (&'static str, String)> {
+    for &hook in hooks {
+        let func = unsafe {
+            version
+                .lib()
+                .get::<fn() -> Result<(), String>>(hook.as_bytes())
+                .map(|func| *func)
+                .map_err(|err| (hook, err.to_string()))?
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(func)) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return Err((hook, err)),
+            Err(_) => return Err((hook, "the hook panicked".to_string())),
+        }
+    }
+
+    Ok(())
+}
@@ -1,62 +1,408 @@
-#![feature(arc_counts)]
+#![feature(arbitrary_self_types, const_fn, futures_api, pin, unboxed_closures)]
 
 pub extern crate libloading;
 pub extern crate parking_lot;
+#[cfg(unix)]
+pub extern crate libc;
 
-use std::sync::atomic::{AtomicPtr, Ordering};
+mod config;
+#[cfg(unix)]
+mod control;
+mod error;
+mod events;
+mod hooks;
+mod panics;
+mod reloader;
+mod scope;
+mod shared;
+#[cfg(unix)]
+mod signal;
+mod slot;
+mod state;
+mod update;
+mod version;
+mod watcher;
+
+pub use config::{AutoRollback, Config, UnloadPolicy};
+pub use error::ReloadError;
+pub use events::{subscribe, ReloadEvent};
+pub use hooks::HookResult;
+pub use panics::{report_panic, PanicReport};
+pub use reloader::ReloadSummary;
+pub use scope::with_pinned_version;
+pub use shared::{Shared, SharedStatic, StaticAddr};
+pub use slot::{FnRef, FnSlot, Slot};
+pub use state::{HotswapState, StateInfo};
+pub use update::{init_library, update_available, Running};
+pub use version::{Version, VersionInfo};
+
+use std::any::Any;
+use std::future::Future;
+use std::mem::PinMut;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+use parking_lot::Mutex;
 
-use libloading::Library;
+use reloader::Reloader;
+use watcher::Command;
 
-// Those are the containers used for the imported libraries.
-trait FnArc: Send + Sync {
-    fn ref_count(&self) -> usize;
+/// Returns from the enclosing hotswapped function once `update_available()`,
+/// with the given value if any. The function can then be called again from
+/// the binary, which runs it in the current version.
+///
+/// ```ignore
+/// #[hotswap]
+/// fn run(world: &mut World) {
+///     loop {
+///         return_on_update!();
+///         world.step();
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! return_on_update {
+    () => {
+        if $crate::update_available() {
+            return;
+        }
+    };
+    ($value:expr) => {
+        if $crate::update_available() {
+            return $value;
+        }
+    };
 }
 
-impl<T: Send + Sync> FnArc for Arc<T> {
-    fn ref_count(&self) -> usize {
-        Arc::strong_count(self)
-    }
+static RELOADER: Mutex<Option<Reloader>> = Mutex::new(None);
+static WATCHER: Mutex<Option<mpsc::Sender<Command>>> = Mutex::new(None);
+
+/// Describes the hotswapped crate, generated by the `hotswap_header` attribute.
+pub struct Manifest {
+    pub crate_name: &'static str,
+    /// The slots of every hotswapped function.
+    pub slots: &'static [&'static Slot],
+    /// The `hotswap_on_load` hooks, run before a version becomes current.
+    pub on_load: &'static [&'static str],
+    /// The `hotswap_on_unload` hooks, run before a version is replaced or
+    /// hotswapping stops.
+    pub on_unload: &'static [&'static str],
+    /// The `hotswap_state` statics, migrated to the new version on reload.
+    pub states: &'static [&'static str],
+    /// The `hotswap_shared` statics, which the library uses from the binary.
+    pub shared: &'static [SharedStatic],
 }
 
-pub struct RefManager {
-    lib: AtomicPtr<Library>,
-    // Contains a copy of every arc to a function in this library
-    // which is no longer stored in the global pointers.
-    refs: Vec<Box<FnArc>>,
+/// Loads the dynamic library and starts watching it for changes, called by the
+/// `hotswap_start!` expansion.
+///
+/// This is unsafe as the library's functions are called with the signatures
+/// given by the manifest, which must match the ones the library was built with.
+pub unsafe fn start(manifest: &'static Manifest, config: Config) -> Hotswap {
+    if let Some(reload_signal) = config.reload_signal {
+        #[cfg(unix)]
+        signal::install(reload_signal).expect("Couldn't install the reload signal handler");
+
+        #[cfg(not(unix))]
+        panic!("Signal {} can't trigger reloads on this platform", reload_signal);
+    }
+
+    #[cfg(unix)]
+    let control = config.control_socket.as_ref().map(|control_socket| {
+        control::spawn(control_socket).expect("Couldn't listen on the control socket")
+    });
+
+    #[cfg(not(unix))]
+    {
+        if let Some(ref control_socket) = config.control_socket {
+            panic!(
+                "Control socket {} is not supported on this platform",
+                control_socket.to_string_lossy()
+            );
+        }
+    }
+
+    let mut reloader = Reloader::new(manifest, config);
+    if let Err(err) = reloader.reload() {
+        panic!("{}", err);
+    }
+    *RELOADER.lock() = Some(reloader);
+
+    let (sender, receiver) = mpsc::channel();
+    *WATCHER.lock() = Some(sender);
+
+    Hotswap {
+        watcher: Some(watcher::spawn(receiver)),
+        #[cfg(unix)]
+        control: control,
+    }
 }
 
-impl RefManager {
-    pub fn new(lib: Library) -> Self {
-        let ptr = Box::into_raw(Box::new(lib));
-        let ptr = AtomicPtr::new(ptr);
+/// Returned by `hotswap_start!`, stops the hotswapping when dropped.
+#[must_use = "hotswapping stops as soon as the handle is dropped"]
+pub struct Hotswap {
+    watcher: Option<JoinHandle<()>>,
+    #[cfg(unix)]
+    control: Option<control::Listener>,
+}
 
-        RefManager {
-            lib: ptr,
-            refs: Vec::new(),
+impl Hotswap {
+    /// The handle `hotswap_start!` returns on lib builds, which do nothing.
+    pub fn inactive() -> Self {
+        Hotswap {
+            watcher: None,
+            #[cfg(unix)]
+            control: None,
         }
     }
 
-    pub fn should_drop(&mut self) -> bool {
-        for i in (0..self.refs.len()).rev() {
-            if self.refs[i].ref_count() == 1 {
-                self.refs.remove(i);
-            } else {
-                return false;
+    /// Stops the watcher thread and unloads every library version, except for
+    /// the pinned ones and the ones still running a call. Hotswapped functions
+    /// panic when called after this.
+    pub fn stop(self) {}
+}
+
+impl Drop for Hotswap {
+    fn drop(&mut self) {
+        let watcher = match self.watcher.take() {
+            Some(watcher) => watcher,
+            None => return,
+        };
+
+        if let Some(sender) = WATCHER.lock().take() {
+            let _ = sender.send(Command::Stop);
+        }
+        let _ = watcher.join();
+
+        #[cfg(unix)]
+        {
+            if let Some(control) = self.control.take() {
+                control.stop();
             }
         }
-        true
+
+        let reloader = RELOADER.lock().take();
+        if let Some(reloader) = reloader {
+            reloader.shutdown();
+        }
+    }
+}
+
+/// Lists the library versions that are still loaded, oldest first.
+pub fn versions() -> Vec<VersionInfo> {
+    match *RELOADER.lock() {
+        Some(ref reloader) => reloader.versions(),
+        None => Vec::new(),
+    }
+}
+
+/// Lists the names of the hotswapped functions.
+pub fn functions() -> Vec<&'static str> {
+    match *RELOADER.lock() {
+        Some(ref reloader) => reloader.functions(),
+        None => Vec::new(),
+    }
+}
+
+/// Keeps a hotswapped function at its current version when the library is
+/// reloaded, until `unfreeze` is called. Returns `false` if there is no
+/// hotswapped function by that name.
+pub fn freeze(name: &str) -> bool {
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.set_frozen(name, true),
+        None => false,
+    }
+}
+
+/// Points a frozen function to the current version again, and lets later
+/// reloads update it. Returns `false` if there is no hotswapped function by
+/// that name, or if the current version doesn't export it anymore, in which
+/// case the function stays frozen.
+pub fn unfreeze(name: &str) -> bool {
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.set_frozen(name, false),
+        None => false,
+    }
+}
+
+/// Makes calls to a hotswapped function run the given closure or function
+/// instead, until `restore_fn` is called. The closure must take the same
+/// argument types and return the same type, and its argument types must be
+/// written out. Returns `false` if there is no hotswapped function by that name,
+/// or if the signature doesn't match, which is always the case for functions
+/// taking references.
+pub fn override_fn<Args: 'static, R: 'static, F>(name: &str, f: F) -> bool
+where
+    F: Fn<Args, Output = R> + Send + Sync + 'static,
+{
+    let func: Box<Any + Send + Sync> =
+        Box::new(Arc::new(f) as Arc<Fn<Args, Output = R> + Send + Sync>);
+
+    match *RELOADER.lock() {
+        Some(ref reloader) => reloader.set_override(name, Some(func)),
+        None => false,
+    }
+}
+
+/// Makes calls to an overridden function run the library version again.
+/// Returns `false` if there is no hotswapped function by that name.
+pub fn restore_fn(name: &str) -> bool {
+    match *RELOADER.lock() {
+        Some(ref reloader) => reloader.set_override(name, None),
+        None => false,
+    }
+}
+
+/// Lists the names of the frozen functions.
+pub fn frozen() -> Vec<&'static str> {
+    match *RELOADER.lock() {
+        Some(ref reloader) => reloader.frozen(),
+        None => Vec::new(),
+    }
+}
+
+/// Keeps a library version loaded until the process exits, returns `false` if
+/// the version is not loaded anymore.
+pub fn pin(generation: usize) -> bool {
+    let version = match *RELOADER.lock() {
+        Some(ref reloader) => reloader.find(generation),
+        None => None,
+    };
+
+    match version {
+        Some(version) => {
+            Version::pin(&version);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Loads the current dynamic library right away, whether it changed or not.
+pub fn reload_now() -> Result<ReloadSummary, ReloadError> {
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.reload(),
+        None => Err(ReloadError::NotStarted),
+    }
+}
+
+/// Makes a previous library version current again, loading it back from its
+/// copy in the `hotswap-dylib` folder if it was already unloaded. Fails with
+/// `ReloadError::AlreadyCurrent` if the version is the current one.
+pub fn rollback(generation: usize) -> Result<ReloadSummary, ReloadError> {
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.rollback(generation),
+        None => Err(ReloadError::NotStarted),
+    }
+}
+
+/// Rolls back to the newest version older than the current one.
+pub fn rollback_previous() -> Result<ReloadSummary, ReloadError> {
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.rollback_previous(),
+        None => Err(ReloadError::NotStarted),
+    }
+}
+
+/// Makes the version prepared by the watcher thread current, when
+/// `Config::safe_points` is set. Call it where no hotswapped function is
+/// running and a swap is harmless, e.g. at the start of every frame. Returns
+/// `None` when no version was waiting.
+pub fn safe_point() -> Option<Result<ReloadSummary, ReloadError>> {
+    if !reloader::PREPARED.load(Ordering::SeqCst) {
+        return None;
     }
 
-    pub fn add_ref<T: 'static + Send + Sync>(&mut self, arc: Arc<T>) {
-        self.refs.push(Box::new(arc));
+    match *RELOADER.lock() {
+        Some(ref mut reloader) => reloader.commit_prepared(),
+        None => None,
     }
 }
 
-impl Drop for RefManager {
+/// Schedules a reload on the watcher thread, without waiting for it. The
+/// returned handle is a future resolving to the result of the reload, or can
+/// be waited on from synchronous code.
+pub fn reload_async() -> PendingReload {
+    let (sender, receiver) = mpsc::channel();
+    let waker = Arc::new(Mutex::new(None));
+
+    let result = ReloadResult {
+        sender: Some(sender),
+        waker: waker.clone(),
+    };
+
+    if let Some(ref watcher) = *WATCHER.lock() {
+        let _ = watcher.send(Command::Reload(result));
+    }
+
+    PendingReload {
+        result: receiver,
+        waker: waker,
+    }
+}
+
+/// A reload scheduled by `reload_async`.
+pub struct PendingReload {
+    result: Receiver<Result<ReloadSummary, ReloadError>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl PendingReload {
+    /// Blocks until the reload is done.
+    pub fn wait(self) -> Result<ReloadSummary, ReloadError> {
+        self.result.recv().unwrap_or(Err(ReloadError::NotStarted))
+    }
+
+    /// Returns the result of the reload if it is done, without blocking.
+    pub fn try_wait(&self) -> Option<Result<ReloadSummary, ReloadError>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(ReloadError::NotStarted)),
+        }
+    }
+}
+
+impl Future for PendingReload {
+    type Output = Result<ReloadSummary, ReloadError>;
+
+    fn poll(self: PinMut<Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // Registered before checking, so a result sent in between still wakes
+        // the task.
+        *self.waker.lock() = Some(cx.waker().clone());
+
+        match self.try_wait() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// The watcher's end of a `PendingReload`, which wakes the waiting task once the
+// result is sent, or once the reload is dropped because the watcher stopped.
+pub(crate) struct ReloadResult {
+    sender: Option<Sender<Result<ReloadSummary, ReloadError>>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl ReloadResult {
+    pub fn send(mut self, result: Result<ReloadSummary, ReloadError>) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(result);
+        }
+    }
+}
+
+impl Drop for ReloadResult {
     fn drop(&mut self) {
-        unsafe {
-            Box::from_raw(self.lib.load(Ordering::Relaxed));
+        // Disconnected first, so the woken task doesn't find the channel empty.
+        self.sender = None;
+
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
         }
     }
 }
@@ -0,0 +1,60 @@
+use std::any::Any;
+use std::sync::Arc;
+
+use events::{self, ReloadEvent};
+use version::{self, Version};
+use watcher::Command;
+use WATCHER;
+
+/// Describes a panic caught in a `#[hotswap(catch_panic)]` function.
+#[derive(Clone, Debug)]
+pub struct PanicReport {
+    pub function: &'static str,
+    /// The generation of the library version that panicked.
+    pub generation: usize,
+    pub message: String,
+}
+
+/// Reports a panic caught in a hotswapped function, called by the generated
+/// code before the panic is resumed, replaced by a fallback value or retried.
+/// The watcher thread then rolls back to the previous version if
+/// `Config::auto_rollback` says so.
+pub fn report_panic(function: &'static str, version: &Arc<Version>, panic: &(Any + Send)) {
+    let report = PanicReport {
+        function: function,
+        generation: version.generation(),
+        message: panic_message(panic),
+    };
+
+    eprintln!(
+        "hotswap: `{}` panicked in library generation {}: {}",
+        report.function, report.generation, report.message
+    );
+
+    let generation = report.generation;
+    events::emit(ReloadEvent::Panicked(report));
+
+    // The reloader may be locked by this very thread, e.g. when a hook called
+    // the function that panicked.
+    if let Some(ref watcher) = *WATCHER.lock() {
+        let _ = watcher.send(Command::Panicked(generation));
+    }
+}
+
+// The newest loaded version older than the given one.
+pub fn previous_version(version: &Version) -> Option<Arc<Version>> {
+    version::loaded()
+        .into_iter()
+        .filter(|loaded| loaded.generation() < version.generation())
+        .max_by_key(|loaded| loaded.generation())
+}
+
+fn panic_message(panic: &(Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<Any>".to_string()
+    }
+}
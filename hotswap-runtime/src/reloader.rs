@@ -0,0 +1,835 @@
+use std::any::Any;
+use std::env::current_exe;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use libloading::Library;
+
+use config::{AutoRollback, Config, UnloadPolicy};
+use error::ReloadError;
+use events::{self, ReloadEvent};
+use hooks;
+use slot::{self, Slot, CURRENT, SWAP_LOCK};
+use state;
+use update;
+use version::{self, Version, VersionInfo};
+use Manifest;
+
+// Set while a version waits for the next safe point, so `safe_point()` doesn't
+// need to lock the reloader on every call.
+pub static PREPARED: AtomicBool = AtomicBool::new(false);
+
+/// Describes a successful reload or rollback.
+#[derive(Clone, Debug)]
+pub struct ReloadSummary {
+    pub generation: usize,
+    /// The generation that was current before the reload.
+    pub previous: Option<usize>,
+    /// The path of the library copy that was loaded.
+    pub path: PathBuf,
+    /// The hotswapped functions pointed to the new version, which excludes the
+    /// frozen ones.
+    pub functions: Vec<&'static str>,
+    pub duration: Duration,
+}
+
+// Loads new copies of the dynamic library and points the slots to them.
+pub struct Reloader {
+    config: Config,
+    manifest: &'static Manifest,
+    dylib_file: PathBuf,
+    tmp_path: PathBuf,
+    last_modified: SystemTime,
+    next_generation: usize,
+    current: Option<Arc<Version>>,
+    // Replaced versions the unload policy still keeps loaded, along with the
+    // time they were replaced.
+    retired: Vec<(Arc<Version>, Instant)>,
+    // When the panics caught in the current version happened, for the automatic
+    // rollback.
+    panics: Vec<Instant>,
+    // The version loaded by the watcher thread, waiting for the next safe point,
+    // and the library modification time to go back to if it can't be swapped in.
+    prepared: Option<(Arc<Version>, SystemTime)>,
+    // The generation last made current by a reload, rather than a rollback, the
+    // only one rolled back automatically.
+    newest: Option<usize>,
+}
+
+impl Reloader {
+    pub fn new(manifest: &'static Manifest, config: Config) -> Self {
+        let exe = current_exe().expect("Couldn't find current executable name");
+        let dir = exe.parent().expect("Couldn't find executable path");
+
+        let dylib_file = dir.join(dylib_name(manifest.crate_name, ""));
+
+        if !dylib_file.exists() {
+            panic!(
+                "Couldn't find file {} - did you add a `[lib]` section to your Cargo.toml?",
+                dylib_file.to_string_lossy()
+            )
+        }
+
+        let last_modified = fs::metadata(&dylib_file)
+            .expect(&format!(
+                "Couldn't read metadata for {}",
+                dylib_file.to_string_lossy()
+            ))
+            .modified()
+            .unwrap();
+
+        Reloader {
+            config: config,
+            manifest: manifest,
+            dylib_file: dylib_file,
+            tmp_path: dir.join("hotswap-dylib"),
+            last_modified: last_modified,
+            next_generation: 0,
+            current: None,
+            retired: Vec::new(),
+            panics: Vec::new(),
+            prepared: None,
+            newest: None,
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<ReloadSummary, ReloadError> {
+        let result = self.reload_latest();
+        events::report(&result, ReloadEvent::Reloaded);
+        result
+    }
+
+    pub fn rollback(&mut self, generation: usize) -> Result<ReloadSummary, ReloadError> {
+        let result = self.load_generation(generation);
+        events::report(&result, ReloadEvent::RolledBack);
+        result
+    }
+
+    pub fn rollback_previous(&mut self) -> Result<ReloadSummary, ReloadError> {
+        let current = self.current.as_ref().map_or(0, |current| current.generation());
+        let mut result = Err(ReloadError::NoPreviousVersion);
+
+        for generation in (0..current).rev() {
+            match self.load_generation(generation) {
+                Err(ReloadError::UnknownVersion(_)) => continue,
+                generation_result => {
+                    result = generation_result;
+                    break;
+                }
+            }
+        }
+
+        events::report(&result, ReloadEvent::RolledBack);
+        result
+    }
+
+    // Loads the latest library without making it current, the swap is done by
+    // `commit_prepared` at the next safe point.
+    pub fn prepare(&mut self) -> Result<(), ReloadError> {
+        let last_modified = self.last_modified;
+        let result = self.load_latest().and_then(|version| {
+            self.check_symbols(&version)?;
+            Ok(version)
+        });
+
+        match result {
+            Ok(version) => {
+                // A version prepared earlier is unloaded right away, it never ran.
+                self.prepared = Some((version, last_modified));
+                PREPARED.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(err) => {
+                // The next poll tries again.
+                self.last_modified = last_modified;
+                events::emit(ReloadEvent::Failed(err.to_string()));
+                Err(err)
+            }
+        }
+    }
+
+    pub fn commit_prepared(&mut self) -> Option<Result<ReloadSummary, ReloadError>> {
+        let (version, last_modified) = self.prepared.take()?;
+        PREPARED.store(false, Ordering::SeqCst);
+
+        let result = self.activate(version.clone(), Instant::now());
+        match result {
+            Ok(ref summary) => self.newest = Some(summary.generation),
+            // The calls still running may have returned by the next safe point.
+            Err(ReloadError::NotQuiescent(_)) => {
+                self.prepared = Some((version, last_modified));
+                PREPARED.store(true, Ordering::SeqCst);
+            }
+            // Otherwise the next poll loads the library again.
+            Err(_) => self.last_modified = last_modified,
+        }
+
+        events::report(&result, ReloadEvent::Reloaded);
+        Some(result)
+    }
+
+    // Reloads once the library changed or the reload signal was received, or
+    // only prepares the new version if the swap waits for a safe point.
+    pub fn auto_reload(&mut self) -> Result<(), ReloadError> {
+        if self.config.safe_points {
+            self.prepare()
+        } else {
+            self.reload().map(|_| ())
+        }
+    }
+
+    fn reload_latest(&mut self) -> Result<ReloadSummary, ReloadError> {
+        let started_at = Instant::now();
+        let last_modified = self.last_modified;
+
+        let result = self
+            .load_latest()
+            .and_then(|version| self.activate(version, started_at));
+        match result {
+            Ok(ref summary) => self.newest = Some(summary.generation),
+            // The next poll tries again.
+            Err(_) => self.last_modified = last_modified,
+        }
+
+        result
+    }
+
+    fn load_latest(&mut self) -> Result<Arc<Version>, ReloadError> {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+
+        // Windows locks the dynamic library once it is loaded, so
+        // I'm creating a copy for now.
+        let copy_name = dylib_name(self.manifest.crate_name, &generation.to_string());
+
+        fs::create_dir_all(&self.tmp_path).map_err(ReloadError::Copy)?;
+
+        // Taken before copying, so the watcher picks up a library rebuilt while
+        // it was being copied, but not the one loaded here.
+        let modified = fs::metadata(&self.dylib_file).and_then(|metadata| metadata.modified());
+
+        let dylib_copy = self.tmp_path.join(&copy_name);
+        fs::copy(&self.dylib_file, &dylib_copy).map_err(ReloadError::Copy)?;
+
+        if let Ok(modified) = modified {
+            if modified > self.last_modified {
+                self.last_modified = modified;
+            }
+        }
+
+        // macOS tries to be smart and prevent dylib reloading when the lib being loaded
+        // shares the same id as a previous loaded library, as a workaround we change the
+        // id of newly build libraries right after copying.
+        #[cfg(target_os = "macos")]
+        {
+            use std::process::{Command, Stdio};
+
+            Command::new("install_name_tool")
+                .args(&["-id", &copy_name, dylib_copy.to_str().unwrap()])
+                .stderr(Stdio::null())
+                .output()
+                .expect(
+                    "Error when trying to run `install_name_tool`, hotswap will not \
+                     work properly in  macOS systems in which it is not available.",
+                );
+        }
+
+        load_version(generation, dylib_copy)
+    }
+
+    // Makes a previous generation current again, loading it back from its copy
+    // if it was already unloaded.
+    fn load_generation(&mut self, generation: usize) -> Result<ReloadSummary, ReloadError> {
+        let started_at = Instant::now();
+
+        // Swapping a version with itself would run its hooks and migrate its
+        // state onto itself.
+        if self.current.as_ref().map(|current| current.generation()) == Some(generation) {
+            return Err(ReloadError::AlreadyCurrent(generation));
+        }
+
+        let version = match self.find(generation) {
+            Some(version) => version,
+            None => {
+                let copy_name = dylib_name(self.manifest.crate_name, &generation.to_string());
+                let dylib_copy = self.tmp_path.join(copy_name);
+
+                if generation >= self.next_generation || !dylib_copy.exists() {
+                    return Err(ReloadError::UnknownVersion(generation));
+                }
+
+                load_version(generation, dylib_copy)?
+            }
+        };
+
+        self.activate(version, started_at)
+    }
+
+    fn activate(
+        &mut self,
+        version: Arc<Version>,
+        started_at: Instant,
+    ) -> Result<ReloadSummary, ReloadError> {
+        self.check_symbols(&version)?;
+
+        version
+            .init_runtime()
+            .map_err(|err| ReloadError::MissingFunction("_HOTSWAP_INIT", err))?;
+
+        version
+            .share_statics(self.manifest.shared)
+            .map_err(ReloadError::SharedStatic)?;
+
+        // States moved as is can't be touched by running calls, so swaps that
+        // migrate them always wait for the calls to return.
+        let raw_states =
+            self.current.is_some() && state::has_raw_states(&version, self.manifest.states);
+        let timeout = match self.config.quiescent_swap {
+            None if raw_states => Some(Duration::from_secs(1)),
+            timeout => timeout,
+        };
+
+        // Nothing runs in the library from here on, except for the hooks and the
+        // state migration, until the swap is done.
+        let quiescent = match timeout {
+            Some(timeout) => {
+                Some(slot::quiesce(timeout).ok_or(ReloadError::NotQuiescent(timeout))?)
+            }
+            None => None,
+        };
+
+        // The new version's load hooks see the migrated state.
+        if let Some(ref current) = self.current {
+            hooks::run(current, self.manifest.on_unload)
+                .map_err(|(hook, err)| ReloadError::Hook(hook, err))?;
+
+            state::migrate(current, &version, self.manifest.states);
+        }
+
+        if let Err((hook, err)) = hooks::run(&version, self.manifest.on_load) {
+            // The state goes back to the version that stays current, which is
+            // loaded again as its unload hooks ran already.
+            if let Some(ref current) = self.current {
+                state::migrate(&version, current, self.manifest.states);
+
+                if let Err((hook, err)) = hooks::run(current, self.manifest.on_load) {
+                    eprintln!("hotswap: {}", ReloadError::Hook(hook, err));
+                }
+            }
+
+            return Err(ReloadError::Hook(hook, err));
+        }
+
+        {
+            // A quiescent swap holds the lock already.
+            let _swap = match quiescent {
+                Some(_) => None,
+                None => Some(SWAP_LOCK.write()),
+            };
+            for slot in self.live_slots() {
+                slot.load(&version)
+                    .map_err(|err| ReloadError::MissingFunction(slot.name(), err))?;
+            }
+            *CURRENT.write() = Some(version.clone());
+            update::CURRENT_GENERATION.store(version.generation(), Ordering::SeqCst);
+        }
+        drop(quiescent);
+
+        version::register(&version);
+        self.retired.retain(|&(ref retired, _)| !Arc::ptr_eq(retired, &version));
+
+        let summary = ReloadSummary {
+            generation: version.generation(),
+            previous: self.current.as_ref().map(|current| current.generation()),
+            path: version.path().to_path_buf(),
+            functions: self.live_slots().iter().map(|slot| slot.name()).collect(),
+            duration: started_at.elapsed(),
+        };
+
+        self.panics.clear();
+
+        // An explicit reload or rollback replaces the version waiting for a safe
+        // point.
+        self.prepared = None;
+        PREPARED.store(false, Ordering::SeqCst);
+
+        if let Some(old_version) = mem::replace(&mut self.current, Some(version)) {
+            self.retired.push((old_version, Instant::now()));
+            self.collect();
+        }
+
+        Ok(summary)
+    }
+
+    // Make sure every function can be found before touching any slot, so a
+    // failed reload doesn't leave the program running mixed versions.
+    fn check_symbols(&self, version: &Version) -> Result<(), ReloadError> {
+        for slot in self.live_slots() {
+            version
+                .check_symbol(slot.name())
+                .map_err(|err| ReloadError::MissingFunction(slot.name(), err))?;
+        }
+
+        Ok(())
+    }
+
+    // Counts a panic caught in a function from the given generation, and rolls
+    // back once the newest version panicked too often. Runs on the watcher
+    // thread, as the rollback waits for the panicking call when it is quiescent.
+    pub fn record_panic(
+        &mut self,
+        generation: usize,
+    ) -> Option<Result<ReloadSummary, ReloadError>> {
+        let auto_rollback = self.config.auto_rollback?;
+
+        // Generations taken by failed or prepared versions never ran, so the
+        // newest version is the last one a reload made current.
+        let newest = self.newest == Some(generation)
+            && self.current.as_ref().map(|current| current.generation()) == Some(generation);
+        if !newest {
+            return None;
+        }
+
+        if count_panic(&mut self.panics, Instant::now(), auto_rollback) {
+            Some(self.rollback_previous())
+        } else {
+            None
+        }
+    }
+
+    pub fn reload_if_modified(&mut self) -> Option<Result<(), ReloadError>> {
+        // TODO: use some filesystem notification crate
+        // so it reloads as soon as the file changes.
+        let modified = match fs::metadata(&self.dylib_file) {
+            Ok(metadata) => metadata.modified().expect(
+                "Couldn't get the metadata's modified time on this platform, hot reloading \
+                 will be horribly inefficient. If you want to use hot-reloading anyway, \
+                 file a PR to do something sensible on this platform.",
+            ),
+            _ => return None,
+        };
+
+        // Loading the library records its modification time.
+        if modified > self.last_modified {
+            Some(self.auto_reload())
+        } else {
+            None
+        }
+    }
+
+    pub fn functions(&self) -> Vec<&'static str> {
+        self.manifest.slots.iter().map(|slot| slot.name()).collect()
+    }
+
+    pub fn frozen(&self) -> Vec<&'static str> {
+        self.manifest
+            .slots
+            .iter()
+            .filter(|slot| slot.is_frozen())
+            .map(|slot| slot.name())
+            .collect()
+    }
+
+    // Freezes or unfreezes a function, which jumps to the current version when
+    // unfrozen. Returns `false` if there is no hotswapped function by that name,
+    // or if the current version doesn't export it.
+    pub fn set_frozen(&mut self, name: &str, frozen: bool) -> bool {
+        let slot = match self.slot(name) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        if !frozen {
+            if let Some(ref current) = self.current {
+                let _swap = SWAP_LOCK.write();
+                if slot.load(current).is_err() {
+                    return false;
+                }
+            }
+        }
+
+        slot.set_frozen(frozen);
+        true
+    }
+
+    // Replaces a function with a closure, or restores the library function if
+    // `func` is `None`. Returns `false` if there is no hotswapped function by that
+    // name, or if the closure has a different signature.
+    pub fn set_override(&self, name: &str, func: Option<Box<Any + Send + Sync>>) -> bool {
+        match self.slot(name) {
+            Some(slot) => slot.set_override(func),
+            None => false,
+        }
+    }
+
+    fn slot(&self, name: &str) -> Option<&'static Slot> {
+        self.manifest.slots.iter().cloned().find(|slot| slot.name() == name)
+    }
+
+    // The slots the reloads update, which are the ones that aren't frozen.
+    fn live_slots(&self) -> Vec<&'static Slot> {
+        self.manifest
+            .slots
+            .iter()
+            .cloned()
+            .filter(|slot| !slot.is_frozen())
+            .collect()
+    }
+
+    pub fn find(&self, generation: usize) -> Option<Arc<Version>> {
+        version::loaded()
+            .into_iter()
+            .find(|version| version.generation() == generation)
+    }
+
+    pub fn versions(&self) -> Vec<VersionInfo> {
+        version::loaded()
+            .into_iter()
+            .map(|version| {
+                let current = self.current
+                    .as_ref()
+                    .map_or(false, |current| Arc::ptr_eq(current, &version));
+                let retired = self.retired
+                    .iter()
+                    .any(|&(ref retired, _)| Arc::ptr_eq(retired, &version));
+
+                let in_slots = CURRENT
+                    .read()
+                    .as_ref()
+                    .map_or(false, |in_slots| Arc::ptr_eq(in_slots, &version));
+
+                // Discount the references held by the runtime itself, including
+                // the `CURRENT` one that pinned scopes start from.
+                let held = 1 + current as usize + in_slots as usize + retired as usize;
+
+                VersionInfo {
+                    generation: version.generation(),
+                    path: version.path().to_path_buf(),
+                    loaded_at: version.loaded_at(),
+                    live_refs: Arc::strong_count(&version) - held,
+                    current: current,
+                    pinned: version.is_pinned(),
+                }
+            })
+            .collect()
+    }
+
+    // Empties the slots, every version is unloaded once the calls still running
+    // return, except for the pinned ones. The current version gets to run its
+    // unload hooks first, but it is deactivated even if they fail.
+    pub fn shutdown(self) {
+        if let Some(ref current) = self.current {
+            if let Err((hook, err)) = hooks::run(current, self.manifest.on_unload) {
+                eprintln!("hotswap: {}", ReloadError::Hook(hook, err));
+            }
+        }
+
+        let _swap = SWAP_LOCK.write();
+        for slot in self.manifest.slots {
+            slot.unload();
+        }
+        *CURRENT.write() = None;
+    }
+
+    // Stops holding the retired versions the unload policy allows to unload,
+    // each of them is unloaded as soon as the last call into it returns.
+    pub fn collect(&mut self) {
+        let unload_policy = self.config.unload_policy;
+
+        self.retired.retain(|&(_, retired_at)| match unload_policy {
+            UnloadPolicy::WhenUnreferenced => false,
+            UnloadPolicy::GracePeriod(duration) => retired_at.elapsed() < duration,
+            UnloadPolicy::Never => true,
+        });
+    }
+}
+
+// Adds a panic that happened at `now` to the ones within the window, and tells
+// whether there are enough of them to roll back.
+fn count_panic(panics: &mut Vec<Instant>, now: Instant, auto_rollback: AutoRollback) -> bool {
+    panics.retain(|&panicked_at| now.duration_since(panicked_at) < auto_rollback.window);
+    panics.push(now);
+
+    if panics.len() < auto_rollback.panics {
+        return false;
+    }
+
+    // Don't try again on every following panic if there is nothing to roll
+    // back to.
+    panics.clear();
+    true
+}
+
+fn load_version(generation: usize, path: PathBuf) -> Result<Arc<Version>, ReloadError> {
+    let lib = Library::new(path.to_string_lossy().as_ref()).map_err(ReloadError::Load)?;
+    Ok(Arc::new(Version::new(generation, path, lib)))
+}
+
+#[cfg(target_os = "windows")]
+fn dylib_name(crate_name: &str, suffix: &str) -> String {
+    format!("{}{}.dll", crate_name, suffix)
+}
+
+#[cfg(target_os = "macos")]
+fn dylib_name(crate_name: &str, suffix: &str) -> String {
+    format!("lib{}{}.dylib", crate_name, suffix)
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+fn dylib_name(crate_name: &str, suffix: &str) -> String {
+    format!("lib{}{}.so", crate_name, suffix)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use libc;
+    use parking_lot::Mutex;
+
+    use super::{count_panic, Reloader, PREPARED};
+    use config::{AutoRollback, Config};
+    use error::ReloadError;
+    use slot::{FnSlot, Slot};
+    use version::{test_version as version, Version};
+    use Manifest;
+
+    static MANIFEST: Manifest = Manifest {
+        crate_name: "test",
+        slots: &[],
+        on_load: &[],
+        on_unload: &[],
+        states: &[],
+        shared: &[],
+    };
+
+    // Slots for a function the test executable exports, and one it doesn't.
+    static GETPID: FnSlot<extern "C" fn() -> libc::pid_t> = FnSlot::new("getpid");
+    static MISSING: FnSlot<fn() -> usize> = FnSlot::new("hotswap_missing");
+
+    static SLOTS_MANIFEST: Manifest = Manifest {
+        crate_name: "test",
+        slots: &[&GETPID, &MISSING],
+        on_load: &[],
+        on_unload: &[],
+        states: &[],
+        shared: &[],
+    };
+
+    // Held by the tests using `PREPARED`. The test executable doesn't export
+    // `_HOTSWAP_INIT`, so every version they try to make current fails to.
+    static PREPARED_TESTS: Mutex<()> = Mutex::new(());
+
+    // A reloader whose current version is the given one, without any library
+    // copies to load versions back from.
+    fn reloader(current: &Arc<Version>) -> Reloader {
+        Reloader {
+            config: Config::default(),
+            manifest: &MANIFEST,
+            dylib_file: PathBuf::new(),
+            tmp_path: PathBuf::from("/nonexistent/hotswap-dylib"),
+            last_modified: SystemTime::now(),
+            next_generation: current.generation() + 1,
+            current: Some(current.clone()),
+            retired: Vec::new(),
+            panics: Vec::new(),
+            prepared: None,
+            newest: Some(current.generation()),
+        }
+    }
+
+    #[test]
+    fn rollback_to_current_version_is_rejected() {
+        let current = version(1);
+        let mut reloader = reloader(&current);
+
+        match reloader.rollback(1) {
+            Err(ReloadError::AlreadyCurrent(1)) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+
+        assert!(Arc::ptr_eq(reloader.current.as_ref().unwrap(), &current));
+        assert!(reloader.retired.is_empty());
+    }
+
+    #[test]
+    fn rollback_to_unknown_version_fails() {
+        let current = version(1);
+        let mut reloader = reloader(&current);
+
+        match reloader.rollback(2) {
+            Err(ReloadError::UnknownVersion(2)) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+
+        // Generation 0 was released and its copy is gone.
+        match reloader.rollback(0) {
+            Err(ReloadError::UnknownVersion(0)) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rollback_previous_without_older_version_fails() {
+        let current = version(1);
+        let mut reloader = reloader(&current);
+
+        match reloader.rollback_previous() {
+            Err(ReloadError::NoPreviousVersion) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+
+        assert!(Arc::ptr_eq(reloader.current.as_ref().unwrap(), &current));
+    }
+
+    #[test]
+    fn failed_prepare_keeps_nothing() {
+        let _prepared = PREPARED_TESTS.lock();
+        let current = version(1);
+        let mut reloader = reloader(&current);
+        let last_modified = reloader.last_modified;
+
+        match reloader.prepare() {
+            Err(ReloadError::Copy(_)) => {}
+            result => panic!("unexpected prepare result {:?}", result),
+        }
+
+        assert!(reloader.prepared.is_none());
+        assert!(!PREPARED.load(Ordering::SeqCst));
+        assert_eq!(reloader.last_modified, last_modified);
+        assert!(reloader.commit_prepared().is_none());
+    }
+
+    #[test]
+    fn failed_commit_loads_the_library_again() {
+        let _prepared = PREPARED_TESTS.lock();
+        let current = version(1);
+        let mut reloader = reloader(&current);
+
+        let prepared_at = reloader.last_modified;
+        reloader.last_modified = prepared_at + Duration::from_secs(1);
+        reloader.prepared = Some((version(2), prepared_at));
+        PREPARED.store(true, Ordering::SeqCst);
+
+        match reloader.commit_prepared() {
+            Some(Err(ReloadError::MissingFunction("_HOTSWAP_INIT", _))) => {}
+            result => panic!("unexpected commit result {:?}", result),
+        }
+
+        assert!(reloader.prepared.is_none());
+        assert!(!PREPARED.load(Ordering::SeqCst));
+        assert_eq!(reloader.last_modified, prepared_at);
+        assert!(Arc::ptr_eq(reloader.current.as_ref().unwrap(), &current));
+    }
+
+    #[test]
+    fn failed_reload_keeps_prepared_version() {
+        let _prepared = PREPARED_TESTS.lock();
+        let current = version(1);
+        let mut reloader = reloader(&current);
+
+        let prepared = version(2);
+        reloader.prepared = Some((prepared.clone(), reloader.last_modified));
+        PREPARED.store(true, Ordering::SeqCst);
+
+        // Only a reload that makes another version current replaces it.
+        match reloader.reload() {
+            Err(ReloadError::Copy(_)) => {}
+            result => panic!("unexpected reload result {:?}", result),
+        }
+        match reloader.rollback(0) {
+            Err(ReloadError::UnknownVersion(0)) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+
+        assert!(Arc::ptr_eq(&reloader.prepared.as_ref().unwrap().0, &prepared));
+        assert!(PREPARED.load(Ordering::SeqCst));
+        PREPARED.store(false, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn frozen_functions_arent_live() {
+        let current = version(1);
+        let mut reloader = reloader(&current);
+        reloader.manifest = &SLOTS_MANIFEST;
+
+        assert!(reloader.set_frozen("getpid", true));
+        assert!(reloader.set_frozen("hotswap_missing", true));
+        assert!(!reloader.set_frozen("unknown", true));
+        assert_eq!(reloader.frozen(), ["getpid", "hotswap_missing"]);
+        assert!(reloader.live_slots().is_empty());
+
+        // Unfreezing points the function to the current version.
+        assert!(reloader.set_frozen("getpid", false));
+        assert_eq!(GETPID.generation(), Some(1));
+
+        assert!(!reloader.set_frozen("hotswap_missing", false));
+        assert_eq!(reloader.frozen(), ["hotswap_missing"]);
+
+        let live: Vec<_> = reloader.live_slots().iter().map(|slot| slot.name()).collect();
+        assert_eq!(live, ["getpid"]);
+    }
+
+    #[test]
+    fn rolls_back_newest_version_after_failed_loads() {
+        let current = version(1);
+        let mut reloader = reloader(&current);
+        reloader.config.auto_rollback = Some(AutoRollback {
+            panics: 1,
+            window: Duration::from_secs(1),
+        });
+
+        // Failed loads and prepared versions take generations as well.
+        reloader.next_generation = 4;
+
+        match reloader.record_panic(1) {
+            Some(Err(ReloadError::NoPreviousVersion)) => {}
+            result => panic!("unexpected rollback result {:?}", result),
+        }
+        assert!(reloader.record_panic(3).is_none());
+
+        // Versions made current by a rollback aren't rolled back automatically.
+        reloader.newest = Some(2);
+        assert!(reloader.record_panic(1).is_none());
+    }
+
+    #[test]
+    fn rolls_back_after_enough_panics_within_window() {
+        let auto_rollback = AutoRollback {
+            panics: 3,
+            window: Duration::from_secs(1),
+        };
+        let start = Instant::now();
+        let mut panics = Vec::new();
+
+        assert!(!count_panic(&mut panics, start, auto_rollback));
+        assert!(!count_panic(&mut panics, start + Duration::from_millis(500), auto_rollback));
+        assert!(count_panic(&mut panics, start + Duration::from_millis(900), auto_rollback));
+
+        // The panics that triggered a rollback aren't counted again.
+        assert!(panics.is_empty());
+        assert!(!count_panic(&mut panics, start + Duration::from_millis(950), auto_rollback));
+    }
+
+    #[test]
+    fn forgets_panics_outside_of_window() {
+        let auto_rollback = AutoRollback {
+            panics: 2,
+            window: Duration::from_secs(1),
+        };
+        let start = Instant::now();
+        let mut panics = Vec::new();
+
+        assert!(!count_panic(&mut panics, start, auto_rollback));
+        assert!(!count_panic(&mut panics, start + Duration::from_secs(1), auto_rollback));
+        assert_eq!(panics.len(), 1);
+        assert!(count_panic(&mut panics, start + Duration::from_millis(1500), auto_rollback));
+    }
+}
@@ -0,0 +1,38 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use slot::CURRENT;
+use version::Version;
+
+thread_local! {
+    static PINNED: RefCell<Option<Arc<Version>>> = RefCell::new(None);
+}
+
+/// Runs `f` with every hotswapped function called from this thread resolving
+/// to the library version that is current now, even if a reload happens in
+/// the meantime. Nested calls keep the version of the outermost one.
+pub fn with_pinned_version<R, F: FnOnce() -> R>(f: F) -> R {
+    if pinned_version().is_some() {
+        return f();
+    }
+
+    let current = CURRENT.read().clone();
+    PINNED.with(|pinned| *pinned.borrow_mut() = current);
+
+    // Unpin even if `f` panics.
+    let _scope = Scope;
+    f()
+}
+
+struct Scope;
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        PINNED.with(|pinned| *pinned.borrow_mut() = None);
+    }
+}
+
+// The version calls from this thread resolve to, if inside `with_pinned_version`.
+pub fn pinned_version() -> Option<Arc<Version>> {
+    PINNED.with(|pinned| pinned.borrow().clone())
+}
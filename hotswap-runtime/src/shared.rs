@@ -0,0 +1,57 @@
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// Replaces a `#[hotswap_shared]` static in the library, dereferencing to the
+/// instance of the static in the binary.
+pub struct Shared<T> {
+    name: &'static str,
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> Shared<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Shared {
+            name: name,
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Called by the runtime with the address of the static in the binary.
+    pub fn set(&self, ptr: *const u8) {
+        self.ptr.store(ptr as *mut T, Ordering::SeqCst);
+    }
+}
+
+impl<T: Sync> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        let ptr = self.ptr.load(Ordering::SeqCst);
+
+        if ptr.is_null() {
+            panic!(
+                "Shared static `{}` used before the library was loaded by hotswap",
+                self.name
+            );
+        }
+
+        unsafe { &*ptr }
+    }
+}
+
+/// A `#[hotswap_shared]` static in the binary.
+pub struct SharedStatic {
+    pub name: &'static str,
+    pub value: &'static StaticAddr,
+}
+
+pub trait StaticAddr: Sync {
+    fn addr(&self) -> *const u8;
+}
+
+impl<T: Sync> StaticAddr for T {
+    fn addr(&self) -> *const u8 {
+        self as *const T as *const u8
+    }
+}
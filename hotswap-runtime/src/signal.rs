@@ -0,0 +1,33 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{self, c_int};
+
+// Set by the signal handler, the watcher thread does the actual reload, as
+// almost nothing is safe to call from inside a signal handler.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: c_int) {
+    PENDING.store(true, Ordering::SeqCst);
+}
+
+pub fn install(signal: c_int) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_signal as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn take_pending() -> bool {
+    PENDING.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,574 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+use panics;
+use scope;
+use update::{self, Running};
+use version::Version;
+
+// Held for writing while the slots are updated, so a function read never sees a
+// partially applied reload.
+pub(crate) static SWAP_LOCK: RwLock<()> = RwLock::new(());
+
+// The version the slots point to, updated along with them.
+pub(crate) static CURRENT: RwLock<Option<Arc<Version>>> = RwLock::new(None);
+
+// The function references handed out for calls and not dropped yet, which the
+// quiescent swap waits for.
+static ACTIVE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Set on the thread doing a quiescent swap, so the hooks it runs can still
+    // call hotswapped functions while the swap lock is held.
+    static SWAPPING: Cell<bool> = Cell::new(false);
+}
+
+/// Holds the swap lock once no call is in progress, see `quiesce`.
+pub(crate) struct Quiescent {
+    _swap: RwLockWriteGuard<'static, ()>,
+}
+
+impl Drop for Quiescent {
+    fn drop(&mut self) {
+        SWAPPING.with(|swapping| swapping.set(false));
+    }
+}
+
+// Blocks new calls and waits for the ones in progress to return, giving up
+// once the timeout is over.
+pub(crate) fn quiesce(timeout: Duration) -> Option<Quiescent> {
+    let started_at = Instant::now();
+    let swap = SWAP_LOCK.try_write_for(timeout)?;
+
+    while ACTIVE_CALLS.load(Ordering::SeqCst) > 0 {
+        if started_at.elapsed() >= timeout {
+            return None;
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    SWAPPING.with(|swapping| swapping.set(true));
+    Some(Quiescent { _swap: swap })
+}
+
+/// A function pointer together with a reference to the library version it
+/// lives in, keeping the version loaded while the pointer is in use.
+pub struct FnRef<F> {
+    func: F,
+    version: Arc<Version>,
+    // Whether this reference is used for a call, rather than stored in a slot.
+    call: bool,
+}
+
+impl<F> FnRef<F> {
+    // A reference for a call, counted as in progress until dropped.
+    fn call(func: F, version: Arc<Version>) -> Self {
+        ACTIVE_CALLS.fetch_add(1, Ordering::SeqCst);
+
+        FnRef {
+            func: func,
+            version: version,
+            call: true,
+        }
+    }
+
+    pub fn version(&self) -> &Arc<Version> {
+        &self.version
+    }
+}
+
+impl<F: Clone> Clone for FnRef<F> {
+    fn clone(&self) -> Self {
+        if self.call {
+            FnRef::call(self.func.clone(), self.version.clone())
+        } else {
+            FnRef {
+                func: self.func.clone(),
+                version: self.version.clone(),
+                call: false,
+            }
+        }
+    }
+}
+
+impl<F> Drop for FnRef<F> {
+    fn drop(&mut self) {
+        if self.call {
+            ACTIVE_CALLS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<F> Deref for FnRef<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.func
+    }
+}
+
+/// Holds the current address of a hotswapped function, one static slot is
+/// generated for every function tagged as `hotswap`. `C` is the trait object
+/// type of the closures the function can be overridden with.
+pub struct FnSlot<F, C: ?Sized = ()> {
+    name: &'static str,
+    current: RwLock<Option<FnRef<F>>>,
+    // Frozen slots keep their version when the library is reloaded.
+    frozen: AtomicBool,
+    // An `Arc<C>` called instead of the library function, set by `override_fn`.
+    overridden: RwLock<Option<Box<Any + Send + Sync>>>,
+    _closure: PhantomData<fn(&C)>,
+}
+
+impl<F, C: ?Sized> FnSlot<F, C> {
+    pub const fn new(name: &'static str) -> Self {
+        FnSlot {
+            name: name,
+            current: RwLock::new(None),
+            frozen: AtomicBool::new(false),
+            overridden: RwLock::new(None),
+            _closure: PhantomData,
+        }
+    }
+}
+
+impl<F: Copy, C: ?Sized + 'static> FnSlot<F, C> {
+    /// The closure replacing the library function, if any.
+    pub fn overridden(&self) -> Option<Arc<C>> {
+        match *self.overridden.read() {
+            Some(ref func) => func.downcast_ref::<Arc<C>>().cloned(),
+            None => None,
+        }
+    }
+
+    pub fn get(&self) -> FnRef<F> {
+        // The thread doing a quiescent swap already holds the lock. Pinned calls
+        // take it as well, as the swap may be migrating the state they use.
+        let _swap = if SWAPPING.with(Cell::get) {
+            None
+        } else {
+            Some(SWAP_LOCK.read())
+        };
+
+        if let Some(version) = scope::pinned_version() {
+            return self.get_pinned(version);
+        }
+
+        match *self.current.read() {
+            Some(ref func) => FnRef::call(func.func, func.version.clone()),
+            None => self.not_loaded(),
+        }
+    }
+
+    fn not_loaded(&self) -> ! {
+        panic!(
+            "Hotswapped function `{}` called before `hotswap_start!()` invocation, or after \
+             hotswapping was stopped!",
+            self.name
+        )
+    }
+
+    // Points the slot to a new function, the previous version is released
+    // once every call that already read it returns.
+    fn set(&self, func: F, version: &Arc<Version>) {
+        let func = FnRef {
+            func: func,
+            version: version.clone(),
+            call: false,
+        };
+
+        *self.current.write() = Some(func);
+    }
+
+    // Frozen functions keep their version in pinned scopes as well. The other
+    // ones come from the pinned version, unless it doesn't export them, as the
+    // functions frozen when it was loaded weren't checked.
+    fn get_pinned(&self, version: Arc<Version>) -> FnRef<F> {
+        let current = self.current.read();
+
+        if let Some(ref func) = *current {
+            if self.frozen.load(Ordering::SeqCst) || Arc::ptr_eq(&func.version, &version) {
+                return FnRef::call(func.func, func.version.clone());
+            }
+        }
+
+        match unsafe { version.lib().get::<F>(self.name.as_bytes()) } {
+            Ok(func) => FnRef::call(*func, version.clone()),
+            Err(_) => match *current {
+                Some(ref func) => FnRef::call(func.func, func.version.clone()),
+                None => self.not_loaded(),
+            },
+        }
+    }
+
+    /// The function from the newest loaded version older than the given one,
+    /// which `#[hotswap(catch_panic(retry))]` calls are retried on.
+    pub fn get_previous(&self, version: &Version) -> Option<FnRef<F>> {
+        let version = panics::previous_version(version)?;
+        let func = unsafe { *version.lib().get::<F>(self.name.as_bytes()).ok()? };
+
+        Some(FnRef::call(func, version))
+    }
+}
+
+/// The type erased interface the reloader uses to update the slots.
+pub trait Slot: Sync {
+    fn name(&self) -> &'static str;
+
+    fn load(&self, version: &Arc<Version>) -> io::Result<()>;
+
+    fn unload(&self);
+
+    fn is_frozen(&self) -> bool;
+
+    fn set_frozen(&self, frozen: bool);
+
+    // Takes a boxed `Arc` of the closure type, or `None` to restore the library
+    // function. Returns `false` if the closure type doesn't match.
+    fn set_override(&self, func: Option<Box<Any + Send + Sync>>) -> bool;
+
+    // The generation of the version the slot points to.
+    fn generation(&self) -> Option<usize>;
+}
+
+impl<F: Copy + Send + Sync + 'static, C: ?Sized + 'static> Slot for FnSlot<F, C> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn load(&self, version: &Arc<Version>) -> io::Result<()> {
+        let func = unsafe { *version.lib().get::<F>(self.name.as_bytes())? };
+        self.set(func, version);
+        Ok(())
+    }
+
+    fn unload(&self) {
+        *self.current.write() = None;
+    }
+
+    fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::SeqCst);
+    }
+
+    fn set_override(&self, func: Option<Box<Any + Send + Sync>>) -> bool {
+        match func {
+            Some(ref func) if !func.is::<Arc<C>>() => false,
+            func => {
+                *self.overridden.write() = func;
+                true
+            }
+        }
+    }
+
+    fn generation(&self) -> Option<usize> {
+        self.current
+            .read()
+            .as_ref()
+            .map(|func| func.version.generation())
+    }
+}
+
+impl<F: Copy + Send + Sync + 'static, C: ?Sized + 'static> FnSlot<F, C> {
+    /// Marks a call through this slot as running on the current thread until
+    /// the returned guard is dropped, so `update_available()` knows which slot
+    /// the running version came from.
+    pub fn running(&'static self) -> Running {
+        update::enter(self)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use libc;
+
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+
+    use super::{quiesce, FnSlot, Slot, CURRENT};
+    use scope::with_pinned_version;
+    use update::{init_library, resolved_generation, update_available, CURRENT_GENERATION};
+    use version::{test_version as version, Version};
+
+    // Held by the tests setting `CURRENT`, which pinned scopes start from.
+    static CURRENT_TESTS: Mutex<()> = Mutex::new(());
+
+    fn first() -> usize {
+        1
+    }
+
+    fn second() -> usize {
+        2
+    }
+
+    #[test]
+    fn replaced_version_is_released() {
+        let slot: FnSlot<fn() -> usize> = FnSlot::new("test");
+
+        let v1 = version(1);
+        let released = Arc::downgrade(&v1);
+        slot.set(first, &v1);
+        drop(v1);
+
+        assert_eq!((*slot.get())(), 1);
+        assert!(released.upgrade().is_some());
+
+        slot.set(second, &version(2));
+
+        assert_eq!((*slot.get())(), 2);
+        assert!(released.upgrade().is_none());
+    }
+
+    #[test]
+    fn in_flight_call_keeps_version_alive() {
+        let slot: FnSlot<fn() -> usize> = FnSlot::new("test");
+
+        let v1 = version(1);
+        let released = Arc::downgrade(&v1);
+        slot.set(first, &v1);
+        drop(v1);
+
+        let call = slot.get();
+        slot.set(second, &version(2));
+        assert!(released.upgrade().is_some());
+
+        assert_eq!((*call)(), 1);
+        drop(call);
+        assert!(released.upgrade().is_none());
+    }
+
+    #[test]
+    fn pinned_version_is_never_released() {
+        let slot: FnSlot<fn() -> usize> = FnSlot::new("test");
+
+        let v1 = version(1);
+        let pinned = Arc::downgrade(&v1);
+        slot.set(first, &v1);
+        drop(v1);
+
+        Version::pin(slot.get().version());
+        slot.set(second, &version(2));
+
+        assert!(pinned.upgrade().map_or(false, |v| v.is_pinned()));
+    }
+
+    #[test]
+    fn pinned_scope_ignores_reloads() {
+        extern "C" fn zero() -> libc::pid_t {
+            0
+        }
+
+        let _current = CURRENT_TESTS.lock();
+
+        // Resolved from the pinned version, which is the test executable itself.
+        let slot: FnSlot<extern "C" fn() -> libc::pid_t> = FnSlot::new("getpid");
+
+        let v1 = version(1);
+        slot.set(zero, &v1);
+        *CURRENT.write() = Some(v1);
+
+        with_pinned_version(|| {
+            slot.set(zero, &version(2));
+
+            let func = slot.get();
+            assert_eq!(func.version().generation(), 1);
+            assert!((*func)() > 0);
+        });
+
+        assert_eq!(slot.get().version().generation(), 2);
+        *CURRENT.write() = None;
+    }
+
+    #[test]
+    fn quiescent_swap_waits_for_calls() {
+        let slot: Arc<FnSlot<fn() -> usize>> = Arc::new(FnSlot::new("test"));
+        slot.set(first, &version(1));
+
+        let call = slot.get();
+        assert!(quiesce(Duration::from_millis(50)).is_none());
+
+        let returned = {
+            let slot = slot.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                drop(call);
+
+                // Blocked until the swap is done.
+                (*slot.get())()
+            })
+        };
+
+        let quiescent = quiesce(Duration::from_secs(5)).expect("the call didn't return");
+        slot.set(second, &version(2));
+        drop(quiescent);
+
+        assert_eq!(returned.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn pinned_scope_keeps_frozen_functions() {
+        let _current = CURRENT_TESTS.lock();
+
+        // Neither version exports `test`, so it can only come from the slot.
+        let slot: FnSlot<fn() -> usize> = FnSlot::new("test");
+        slot.set(first, &version(1));
+        slot.set_frozen(true);
+        *CURRENT.write() = Some(version(2));
+
+        with_pinned_version(|| {
+            let func = slot.get();
+            assert_eq!(func.version().generation(), 1);
+            assert_eq!((*func)(), 1);
+        });
+
+        // Unfrozen functions the pinned version lacks come from the slot as well.
+        slot.set_frozen(false);
+        slot.set(second, &version(3));
+
+        with_pinned_version(|| {
+            let func = slot.get();
+            assert_eq!(func.version().generation(), 3);
+            assert_eq!((*func)(), 2);
+        });
+
+        *CURRENT.write() = None;
+    }
+
+    #[test]
+    fn quiescent_swap_blocks_pinned_calls() {
+        let _current = CURRENT_TESTS.lock();
+
+        let slot: Arc<FnSlot<fn() -> usize>> = Arc::new(FnSlot::new("test"));
+        let v1 = version(1);
+        slot.set(first, &v1);
+        *CURRENT.write() = Some(v1);
+
+        let quiescent = quiesce(Duration::from_secs(5)).expect("a call is in progress");
+        let called = Arc::new(AtomicBool::new(false));
+
+        let returned = {
+            let slot = slot.clone();
+            let called = called.clone();
+            thread::spawn(move || {
+                with_pinned_version(|| {
+                    let func = slot.get();
+                    called.store(true, Ordering::SeqCst);
+                    (*func)()
+                })
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!called.load(Ordering::SeqCst));
+        drop(quiescent);
+
+        assert_eq!(returned.join().unwrap(), 1);
+        *CURRENT.write() = None;
+    }
+
+    #[test]
+    fn override_replaces_library_function() {
+        let slot: FnSlot<fn() -> usize, Fn() -> usize + Send + Sync> = FnSlot::new("test");
+        slot.set(first, &version(1));
+
+        let closure: Arc<Fn() -> usize + Send + Sync> = Arc::new(|| 3);
+        assert!(slot.set_override(Some(Box::new(closure))));
+        assert_eq!((*slot.overridden().unwrap())(), 3);
+
+        let mismatched: Arc<Fn(usize) -> usize + Send + Sync> = Arc::new(|x| x);
+        assert!(!slot.set_override(Some(Box::new(mismatched))));
+        assert_eq!((*slot.overridden().unwrap())(), 3);
+
+        assert!(slot.set_override(None));
+        assert!(slot.overridden().is_none());
+        assert_eq!((*slot.get())(), 1);
+    }
+
+    // The library's copy of the runtime is this same one in the tests.
+    #[test]
+    fn update_follows_the_running_slot() {
+        static SLOT: FnSlot<fn() -> usize> = FnSlot::new("running");
+
+        SLOT.set(first, &version(3));
+        init_library(3, resolved_generation);
+        CURRENT_GENERATION.store(4, Ordering::SeqCst);
+
+        // Like a frozen slot, which stays at its version after a reload.
+        let running = SLOT.running();
+        assert!(!update_available());
+
+        SLOT.set(second, &version(4));
+        assert!(update_available());
+
+        drop(running);
+        assert!(update_available());
+    }
+
+    #[test]
+    fn concurrent_callers_release_every_version() {
+        const CALLERS: usize = 8;
+        const RELOADS: usize = 200;
+
+        let slot: Arc<FnSlot<fn() -> usize>> = Arc::new(FnSlot::new("test"));
+        slot.set(first, &version(0));
+
+        let done = Arc::new(AtomicBool::new(false));
+        let barrier = Arc::new(Barrier::new(CALLERS + 1));
+
+        let callers: Vec<_> = (0..CALLERS)
+            .map(|_| {
+                let slot = slot.clone();
+                let done = done.clone();
+                let barrier = barrier.clone();
+
+                thread::spawn(move || {
+                    barrier.wait();
+                    while !done.load(Ordering::SeqCst) {
+                        let func = slot.get();
+                        let result = (*func)();
+                        assert!(result == 1 || result == 2);
+                    }
+                })
+            })
+            .collect();
+
+        barrier.wait();
+
+        let mut released = Vec::new();
+        for generation in 1..RELOADS + 1 {
+            let version = version(generation);
+            released.push(Arc::downgrade(&version));
+
+            let func = if generation % 2 == 0 { first } else { second };
+            slot.set(func, &version);
+        }
+
+        done.store(true, Ordering::SeqCst);
+        for caller in callers {
+            caller.join().unwrap();
+        }
+
+        // Only the version still stored in the slot may be alive.
+        let alive = released.iter().filter(|v| v.upgrade().is_some()).count();
+        assert_eq!(alive, 1);
+        assert!(released.last().unwrap().upgrade().is_some());
+    }
+}
@@ -0,0 +1,204 @@
+use std::ptr;
+use std::sync::Arc;
+
+use version::Version;
+
+/// Implemented by the types of `#[hotswap_state(serialize)]` statics, whose
+/// value is handed to the new library version through `save` and `restore`,
+/// so their layout can change between versions.
+pub trait HotswapState: Sync {
+    fn save(&self) -> Vec<u8>;
+
+    fn restore(&self, data: &[u8]);
+}
+
+/// Describes a `#[hotswap_state]` static, exported by the library for each of
+/// them.
+pub struct StateInfo {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    type_name: &'static str,
+    save: Option<fn(*const u8) -> Vec<u8>>,
+    restore: Option<fn(*const u8, &[u8])>,
+    pins_library: bool,
+}
+
+impl StateInfo {
+    /// The value is moved to the new version as is, when its layout and type
+    /// name are unchanged. The static must be a `static mut`, which the plugin
+    /// checks, as other statics may be in read-only memory.
+    pub fn raw<T: Sync>(value: &'static T, type_name: &'static str) -> Self {
+        StateInfo {
+            ptr: value as *const T as *mut u8,
+            size: ::std::mem::size_of::<T>(),
+            align: ::std::mem::align_of::<T>(),
+            type_name: type_name,
+            save: None,
+            restore: None,
+            pins_library: false,
+        }
+    }
+
+    /// The value may point into the library, e.g. a closure or a `&'static str`,
+    /// so moving it keeps the version it was moved out of loaded.
+    pub fn pins_library(self) -> Self {
+        StateInfo {
+            pins_library: true,
+            ..self
+        }
+    }
+
+    pub fn serialized<T: HotswapState>(value: &'static T, type_name: &'static str) -> Self {
+        fn save<T: HotswapState>(ptr: *const u8) -> Vec<u8> {
+            unsafe { (*(ptr as *const T)).save() }
+        }
+
+        fn restore<T: HotswapState>(ptr: *const u8, data: &[u8]) {
+            unsafe { (*(ptr as *const T)).restore(data) }
+        }
+
+        StateInfo {
+            save: Some(save::<T>),
+            restore: Some(restore::<T>),
+            ..StateInfo::raw(value, type_name)
+        }
+    }
+}
+
+// Hands the value of every state static from the old version to the new one.
+// States that can't be migrated keep their initial value in the new version.
+// Raw states are moved as is, so no call may be running in either version.
+pub fn migrate(old: &Arc<Version>, new: &Version, states: &[&'static str]) {
+    for &name in states {
+        let symbol = format!("_HOTSWAP_STATE_{}", name);
+
+        match unsafe { (state_info(old, &symbol), state_info(new, &symbol)) } {
+            (Some(old_info), Some(new_info)) => {
+                if migrate_state(name, &old_info, &new_info) && old_info.pins_library {
+                    Version::pin(old);
+                }
+            }
+            _ => eprintln!("hotswap: Couldn't find state `{}`, it won't be migrated", name),
+        }
+    }
+}
+
+// Whether the version has states that are moved as is, rather than serialized.
+pub fn has_raw_states(version: &Version, states: &[&'static str]) -> bool {
+    states.iter().any(|name| {
+        let symbol = format!("_HOTSWAP_STATE_{}", name);
+        unsafe { state_info(version, &symbol) }.map_or(false, |info| info.save.is_none())
+    })
+}
+
+// Returns whether the value was moved as is.
+fn migrate_state(name: &str, old_info: &StateInfo, new_info: &StateInfo) -> bool {
+    // Both versions are the same library.
+    if old_info.ptr == new_info.ptr {
+        return false;
+    }
+
+    let same_layout = old_info.size == new_info.size && old_info.align == new_info.align
+        && old_info.type_name == new_info.type_name;
+
+    match (old_info.save, new_info.restore) {
+        (Some(save), Some(restore)) => {
+            restore(new_info.ptr, &save(old_info.ptr));
+            false
+        }
+        // The old version gets the initial value of the new one, as statics
+        // are never dropped nothing is freed twice.
+        (None, None) if same_layout => {
+            unsafe { ptr::swap_nonoverlapping(old_info.ptr, new_info.ptr, old_info.size) };
+            true
+        }
+        _ => {
+            eprintln!(
+                "hotswap: The type of state `{}` changed, it will start from its initial value",
+                name
+            );
+            false
+        }
+    }
+}
+
+unsafe fn state_info(version: &Version, symbol: &str) -> Option<StateInfo> {
+    version
+        .lib()
+        .get::<fn() -> StateInfo>(symbol.as_bytes())
+        .ok()
+        .map(|info| info())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{migrate_state, HotswapState, StateInfo};
+
+    struct Counter(AtomicUsize);
+
+    impl HotswapState for Counter {
+        fn save(&self) -> Vec<u8> {
+            vec![self.0.load(Ordering::SeqCst) as u8]
+        }
+
+        fn restore(&self, data: &[u8]) {
+            self.0.store(data[0] as usize, Ordering::SeqCst);
+        }
+    }
+
+    // Plain atomics rather than `static mut`s, so the values can be read back
+    // without aliasing the swapped memory.
+    #[test]
+    fn raw_state_is_swapped() {
+        static OLD: AtomicUsize = AtomicUsize::new(7);
+        static NEW: AtomicUsize = AtomicUsize::new(0);
+
+        let old = StateInfo::raw(&OLD, "AtomicUsize");
+        let new = StateInfo::raw(&NEW, "AtomicUsize");
+        assert!(migrate_state("test", &old, &new));
+
+        assert_eq!(NEW.load(Ordering::SeqCst), 7);
+        assert_eq!(OLD.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn changed_raw_state_keeps_initial_value() {
+        static OLD: AtomicUsize = AtomicUsize::new(7);
+        static NEW: AtomicUsize = AtomicUsize::new(0);
+
+        let old = StateInfo::raw(&OLD, "AtomicUsize");
+        let new = StateInfo::raw(&NEW, "Counter");
+        assert!(!migrate_state("test", &old, &new));
+
+        assert_eq!(NEW.load(Ordering::SeqCst), 0);
+        assert_eq!(OLD.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn serialized_state_is_restored() {
+        static OLD: Counter = Counter(AtomicUsize::new(7));
+        static NEW: Counter = Counter(AtomicUsize::new(0));
+
+        let old = StateInfo::serialized(&OLD, "Counter");
+        let new = StateInfo::serialized(&NEW, "Counter");
+        assert!(!migrate_state("test", &old, &new));
+
+        assert_eq!(NEW.0.load(Ordering::SeqCst), 7);
+        assert_eq!(OLD.0.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn raw_state_isnt_restored_from_serialized_state() {
+        static OLD: Counter = Counter(AtomicUsize::new(7));
+        static NEW: Counter = Counter(AtomicUsize::new(0));
+
+        let old = StateInfo::serialized(&OLD, "Counter");
+        let new = StateInfo::raw(&NEW, "Counter");
+        assert!(!migrate_state("test", &old, &new));
+
+        assert_eq!(NEW.0.load(Ordering::SeqCst), 0);
+    }
+}
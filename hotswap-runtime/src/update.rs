@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::RwLock;
+
+use scope;
+use slot::Slot;
+
+// The generation of the current version, kept by the binary's runtime.
+pub static CURRENT_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+// Every library has its own copy of the runtime, which gets its generation and
+// the binary's `resolved_generation` when the library is loaded.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+static RESOLVED_GENERATION: RwLock<Option<fn() -> usize>> = RwLock::new(None);
+
+thread_local! {
+    // The slots of the calls from the binary running on this thread, innermost
+    // last. Async calls aren't tracked, as their futures may move threads.
+    static RUNNING: RefCell<Vec<&'static Slot>> = RefCell::new(Vec::new());
+}
+
+/// Checks if the library version running the caller was replaced, so a long
+/// running loop in a hotswapped function can return and be called again in
+/// the version that call would run. Always `false` outside of the library,
+/// in frozen functions and in `with_pinned_version` scopes.
+pub fn update_available() -> bool {
+    match *RESOLVED_GENERATION.read() {
+        Some(resolved_generation) => resolved_generation() != GENERATION.load(Ordering::SeqCst),
+        None => false,
+    }
+}
+
+// The generation a call from the binary would run on this thread: the pinned
+// one inside `with_pinned_version`, else the one the slot of the innermost
+// running call points to, which differs from the current one if it is frozen.
+pub fn resolved_generation() -> usize {
+    if let Some(version) = scope::pinned_version() {
+        return version.generation();
+    }
+
+    RUNNING
+        .with(|running| running.borrow().last().and_then(|slot| slot.generation()))
+        .unwrap_or_else(|| CURRENT_GENERATION.load(Ordering::SeqCst))
+}
+
+/// Removes a call from the running ones when dropped.
+pub struct Running {
+    _private: (),
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.borrow_mut().pop());
+    }
+}
+
+pub(crate) fn enter(slot: &'static Slot) -> Running {
+    RUNNING.with(|running| running.borrow_mut().push(slot));
+    Running { _private: () }
+}
+
+/// Called by the `_HOTSWAP_INIT` function exported by the library.
+#[doc(hidden)]
+pub fn init_library(generation: usize, resolved_generation: fn() -> usize) {
+    GENERATION.store(generation, Ordering::SeqCst);
+    *RESOLVED_GENERATION.write() = Some(resolved_generation);
+}
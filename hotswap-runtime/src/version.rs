@@ -0,0 +1,138 @@
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+
+use libloading::Library;
+use parking_lot::Mutex;
+
+use shared::SharedStatic;
+use update;
+
+// Every version loaded so far, including the released ones that are still
+// referenced somewhere. Kept outside of the reloader, so the calls retried by
+// `catch_panic(retry)` can find older versions without locking it.
+static LOADED: Mutex<Option<Vec<Weak<Version>>>> = Mutex::new(None);
+
+/// A snapshot of a loaded library version, as listed by `versions()`.
+#[derive(Clone, Debug)]
+pub struct VersionInfo {
+    pub generation: usize,
+    pub path: PathBuf,
+    pub loaded_at: SystemTime,
+    /// References from function slots, calls in progress and pinned values.
+    pub live_refs: usize,
+    pub current: bool,
+    pub pinned: bool,
+}
+
+/// A loaded copy of the dynamic library.
+///
+/// Every function pointer taken from the library holds a reference to its
+/// version, so the library is unloaded as soon as the last one is dropped.
+pub struct Version {
+    generation: usize,
+    path: PathBuf,
+    loaded_at: SystemTime,
+    pinned: AtomicBool,
+    lib: Library,
+}
+
+impl Version {
+    pub(crate) fn new(generation: usize, path: PathBuf, lib: Library) -> Self {
+        Version {
+            generation: generation,
+            path: path,
+            loaded_at: SystemTime::now(),
+            pinned: AtomicBool::new(false),
+            lib: lib,
+        }
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// The path of the library copy this version was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn loaded_at(&self) -> SystemTime {
+        self.loaded_at
+    }
+
+    /// Keeps the version loaded until the process exits, regardless of the
+    /// unload policy.
+    pub fn pin(version: &Arc<Version>) {
+        if !version.pinned.swap(true, Ordering::SeqCst) {
+            mem::forget(version.clone());
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn check_symbol(&self, name: &str) -> io::Result<()> {
+        unsafe { self.lib.get::<*const u8>(name.as_bytes()).map(|_| ()) }
+    }
+
+    // Hands the generation and the binary's runtime state to the library copy
+    // of the runtime.
+    pub(crate) fn init_runtime(&self) -> io::Result<()> {
+        let init = unsafe { *self.lib.get::<fn(usize, fn() -> usize)>(b"_HOTSWAP_INIT")? };
+        init(self.generation, update::resolved_generation);
+        Ok(())
+    }
+
+    // Points the library copies of the shared statics to the binary ones.
+    pub(crate) fn share_statics(&self, statics: &[SharedStatic]) -> io::Result<()> {
+        for shared in statics {
+            let symbol = format!("_HOTSWAP_SHARED_{}", shared.name);
+            let set = unsafe { *self.lib.get::<fn(*const u8)>(symbol.as_bytes())? };
+            set(shared.value.addr());
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn lib(&self) -> &Library {
+        &self.lib
+    }
+}
+
+// Adds a version to the loaded ones, unless it was loaded before.
+pub(crate) fn register(version: &Arc<Version>) {
+    let mut loaded = LOADED.lock();
+    let loaded = loaded.get_or_insert_with(Vec::new);
+
+    loaded.retain(|loaded| loaded.upgrade().is_some());
+
+    let registered = loaded
+        .iter()
+        .filter_map(Weak::upgrade)
+        .any(|loaded| Arc::ptr_eq(&loaded, version));
+    if !registered {
+        loaded.push(Arc::downgrade(version));
+    }
+}
+
+// The versions that are still loaded, oldest first.
+pub(crate) fn loaded() -> Vec<Arc<Version>> {
+    match *LOADED.lock() {
+        Some(ref loaded) => loaded.iter().filter_map(Weak::upgrade).collect(),
+        None => Vec::new(),
+    }
+}
+
+// A version backed by the test binary itself, for the tests that only need
+// distinct versions.
+#[cfg(all(test, unix))]
+pub(crate) fn test_version(generation: usize) -> Arc<Version> {
+    use libloading::os::unix::Library;
+
+    Arc::new(Version::new(generation, PathBuf::new(), Library::this().into()))
+}
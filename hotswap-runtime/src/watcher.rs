@@ -0,0 +1,91 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use reloader::Reloader;
+#[cfg(unix)]
+use signal;
+use {ReloadResult, RELOADER};
+
+const POLL_INTERVAL: u64 = 5000;
+
+pub enum Command {
+    Reload(ReloadResult),
+    // A panic caught in the given generation, counted for the automatic
+    // rollback here rather than on the panicking thread.
+    Panicked(usize),
+    Stop,
+}
+
+// Polls the library for changes, and runs the commands sent by the other threads.
+pub fn spawn(commands: Receiver<Command>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let interval = Duration::from_millis(POLL_INTERVAL);
+        let mut next_poll = Instant::now() + interval;
+
+        loop {
+            // Commands don't delay the polling, even if they keep coming.
+            let now = Instant::now();
+            let timeout = if next_poll > now {
+                next_poll - now
+            } else {
+                Duration::from_millis(0)
+            };
+
+            let command = match commands.recv_timeout(timeout) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let mut reloader = RELOADER.lock();
+            let reloader = match *reloader {
+                Some(ref mut reloader) => reloader,
+                None => break,
+            };
+
+            match command {
+                Some(Command::Reload(result)) => {
+                    result.send(reloader.reload());
+                }
+                Some(Command::Panicked(generation)) => {
+                    match reloader.record_panic(generation) {
+                        Some(Ok(summary)) => eprintln!(
+                            "hotswap: rolled back to library generation {} after repeated panics",
+                            summary.generation
+                        ),
+                        Some(Err(err)) => eprintln!("hotswap: {}", err),
+                        None => {}
+                    }
+                }
+                Some(Command::Stop) => break,
+                None => {}
+            }
+
+            if Instant::now() >= next_poll {
+                poll(reloader);
+                next_poll = Instant::now() + interval;
+            }
+        }
+    })
+}
+
+fn poll(reloader: &mut Reloader) {
+    // Release the retired versions whose grace period is over.
+    reloader.collect();
+
+    #[cfg(unix)]
+    let signaled = signal::take_pending();
+    #[cfg(not(unix))]
+    let signaled = false;
+
+    let result = if signaled {
+        Some(reloader.auto_reload())
+    } else {
+        reloader.reload_if_modified()
+    };
+
+    if let Some(Err(err)) = result {
+        eprintln!("hotswap: {}", err);
+    }
+}
@@ -1,236 +1,361 @@
-use syntax::ast::{Block, Expr, Ident, Item, Name};
+use syntax::ast::{Block, Expr, Ident, Item, ItemKind, Name, Ty};
+use syntax::codemap::DUMMY_SP;
 use syntax::ext::base::ExtCtxt;
+use syntax::ext::build::AstBuilder;
+use syntax::print::pprust;
 use syntax::ptr::P;
+use syntax::tokenstream::TokenTree;
+
+use std::mem;
 
 use util::rustc::crate_name;
-use util::syntax::comma_separated_tokens;
+use util::syntax::{comma_separated_tokens, static_escapes_library};
 
-use HotswapFnInfo;
+use {CatchPanic, HotswapFnInfo, HotswapManifest};
 
-// Creates a module with the runtime structs and a static pointer for each hotswapped function.
-pub fn runtime_mod(cx: &mut ExtCtxt, hotswap_fns: &[HotswapFnInfo]) -> P<Item> {
+// Creates a module with a static slot for each hotswapped function, which stores the
+// function address during runtime.
+pub fn runtime_mod(
+    cx: &mut ExtCtxt,
+    hotswap_fns: &[HotswapFnInfo],
+    manifest: &HotswapManifest,
+) -> P<Item> {
     let mut static_items = Vec::new();
+    let mut slot_refs = Vec::new();
 
     for hotswap_fn in hotswap_fns {
-        let pointer_ident = pointer_ident(&hotswap_fn.name);
+        let pointer_name = &hotswap_fn.name;
+        let pointer_ident = pointer_ident(pointer_name);
         let input_types = &hotswap_fn.input_types;
-        let output_types = &hotswap_fn.output_type;
+        let output_types = pointer_output_type(cx, hotswap_fn);
 
         let item = quote_item!(cx,
             #[allow(non_upper_case_globals)]
-            pub static $pointer_ident: RwLock<Option<Arc<fn($input_types) -> $output_types>>> =
-                RwLock::new(None);
+            pub static $pointer_ident: FnSlot<
+                fn($input_types) -> $output_types,
+                Fn($input_types) -> $output_types + Send + Sync,
+            > = FnSlot::new($pointer_name);
         ).unwrap();
 
         static_items.push(item);
+        slot_refs.push(quote_expr!(cx, &$pointer_ident));
     }
 
+    let slot_refs = comma_separated_tokens(cx, &slot_refs);
+    let on_load = comma_separated_tokens(cx, &manifest.on_load);
+    let on_unload = comma_separated_tokens(cx, &manifest.on_unload);
+    let states = comma_separated_tokens(cx, &manifest.states);
+    let dispatch_statics = dispatch_statics(cx, hotswap_fns);
+
+    // The dispatch statics are shared with the library like the user's statics.
+    let mut shared = manifest.shared.clone();
+    for hotswap_fn in hotswap_fns.iter().filter(|hotswap_fn| hotswap_fn.options.dispatch) {
+        let dispatch_ident = dispatch_ident(&hotswap_fn.name);
+        let runtime_ident = Ident::with_empty_ctxt(Name::intern("_HOTSWAP_RUNTIME"));
+        shared.push((format!("{}", dispatch_ident.name), vec![runtime_ident, dispatch_ident]));
+    }
+    let shared = shared_statics(cx, &shared);
+    let crate_name = crate_name();
+
     quote_item!(cx,
         #[allow(non_snake_case)]
         #[allow(dead_code)]
         mod _HOTSWAP_RUNTIME {
-            use ::std::sync::Arc;
-            use ::hotswap_runtime::parking_lot::RwLock;
+            use ::hotswap_runtime::{FnSlot, Manifest};
 
             $static_items
+
+            $dispatch_statics
+
+            pub static MANIFEST: Manifest = Manifest {
+                crate_name: $crate_name,
+                slots: &[$slot_refs],
+                on_load: &[$on_load],
+                on_unload: &[$on_unload],
+                states: &[$states],
+                shared: &[$shared],
+            };
         }
     ).unwrap()
 }
 
 pub fn fn_body(cx: &mut ExtCtxt, fn_info: &HotswapFnInfo) -> P<Block> {
-    let pointer_name = &fn_info.name;
-    let pointer_ident = pointer_ident(pointer_name);
+    let pointer_ident = pointer_ident(&fn_info.name);
     let input_idents = comma_separated_tokens(cx, &fn_info.input_idents);
 
+    // The function reference is kept alive inside the async function state until
+    // the future is dropped, so the library that created the future stays loaded.
+    let mut call = if fn_info.is_async {
+        quote_expr!(cx, await!(func($input_idents)))
+    } else {
+        quote_expr!(cx, func($input_idents))
+    };
+
+    // Closures set by `override_fn` live in the binary, so no version is needed.
+    let overridden_call = call.clone();
+
+    // There is no telling when closures or references created by the library
+    // will be dropped, so keep the library loaded forever.
+    if fn_info.pins_library {
+        call = quote_expr!(cx, {
+            let result = $call;
+            ::hotswap_runtime::Version::pin(func.version());
+            result
+        });
+    }
+
+    if let Some(catch_panic) = fn_info.options.catch_panic {
+        call = catch_panic_call(cx, fn_info, call, catch_panic);
+    }
+
+    // `update_available()` checks the slot of the innermost running call, async
+    // calls aren't tracked since their futures may move between threads.
+    let running = if fn_info.is_async {
+        quote_expr!(cx, ())
+    } else {
+        quote_expr!(cx, ::_HOTSWAP_RUNTIME::$pointer_ident.running())
+    };
+
     P(quote_block!(cx, {
-        let func = {
-            let guard = ::_HOTSWAP_RUNTIME::$pointer_ident.read();
-            match *guard {
-                Some(ref arc) => arc.clone(),
-                None => panic!(
-                    "Hotswapped function `{}` called before `hotswap_start!()` invocation!",
-                    $pointer_name
-                )
-            }
-        };
+        if let Some(func) = ::_HOTSWAP_RUNTIME::$pointer_ident.overridden() {
+            return $overridden_call;
+        }
+
+        let _running = $running;
+        let func = ::_HOTSWAP_RUNTIME::$pointer_ident.get();
 
-        func($input_idents)
+        $call
     }).into_inner())
 }
 
-pub fn macro_expansion(cx: &mut ExtCtxt, hotswap_fns: &[HotswapFnInfo]) -> P<Expr> {
-    let mut ref_updaters = Vec::new();
-
-    // Create one statement per hotswapped function, each
-    // statement will update its global variable to point
-    // to the latest dynamic address, and save the previous
-    // reference so it can be used for refcounting.
-    for fn_info in hotswap_fns.iter() {
-        let pointer_name = &fn_info.name;
-        let pointer_ident = pointer_ident(pointer_name);
-        let input_types = comma_separated_tokens(cx, &fn_info.input_types);
-        let output_type = &fn_info.output_type;
-
-        let stmt = quote_stmt!(cx, {
-            let fn_address =
-                *lib.get::<fn($input_types) -> $output_type>($pointer_name.as_bytes())
-                .expect(&format!(
-                    "Couldn't find function `{}` on hotswapped library",
-                    $pointer_name
-                )).deref();
-
-            let mut pointer_guard = ::_HOTSWAP_RUNTIME::$pointer_ident.write();
-            let new_ref = Some(Arc::new(fn_address));
-            let prev_ref = mem::replace(&mut *pointer_guard, new_ref);
-
-            if let Some(ref mut lib) = current_lib {
-                if let Some(arc) = prev_ref {
-                    lib.add_ref(arc);
+// Runs the call inside `catch_unwind`, so a panic in the library doesn't unwind
+// through the forwarding function. Retried calls need their own copy of the
+// arguments, the originals are moved into the call that panicked.
+fn catch_panic_call(
+    cx: &mut ExtCtxt,
+    fn_info: &HotswapFnInfo,
+    call: P<Expr>,
+    catch_panic: CatchPanic,
+) -> P<Expr> {
+    let pointer_ident = pointer_ident(&fn_info.name);
+    let name = &fn_info.name;
+
+    let on_panic = match catch_panic {
+        CatchPanic::Resume => quote_expr!(cx, ::std::panic::resume_unwind(panic)),
+        CatchPanic::Fallback => quote_expr!(cx, ::std::default::Default::default()),
+        CatchPanic::Retry => {
+            let arg_pats = fn_info
+                .input_idents
+                .iter()
+                .map(|&ident| cx.pat_ident(DUMMY_SP, ident))
+                .collect();
+            let arg_pats = cx.pat_tuple(DUMMY_SP, arg_pats);
+
+            quote_expr!(cx,
+                match ::_HOTSWAP_RUNTIME::$pointer_ident.get_previous(func.version()) {
+                    Some(func) => {
+                        let $arg_pats = retry_args;
+                        $call
+                    }
+                    None => ::std::panic::resume_unwind(panic),
                 }
+            )
+        }
+    };
+
+    let caught = quote_expr!(cx,
+        match ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| $call)) {
+            Ok(result) => result,
+            Err(panic) => {
+                ::hotswap_runtime::report_panic($name, func.version(), &*panic);
+                $on_panic
             }
-        }).unwrap();
+        }
+    );
+
+    if catch_panic == CatchPanic::Retry {
+        let arg_clones = fn_info
+            .input_idents
+            .iter()
+            .map(|&ident| quote_expr!(cx, $ident.clone()))
+            .collect();
+        let retry_args = cx.expr_tuple(DUMMY_SP, arg_clones);
+
+        quote_expr!(cx, {
+            let retry_args = $retry_args;
+            $caught
+        })
+    } else {
+        caught
+    }
+}
 
-        ref_updaters.push(stmt);
+// The library side of a `#[hotswap(dispatch)]` function: a function with its
+// name that calls the function in the binary, which dispatches the call to the
+// current version, and the static holding the address of the latter.
+pub fn dispatch_forward(cx: &mut ExtCtxt, item: &Item, fn_info: &HotswapFnInfo) -> Vec<P<Item>> {
+    let dispatch_ident = dispatch_ident(&fn_info.name);
+    let dispatch_name = format!("{}", dispatch_ident.name);
+    let input_types = comma_separated_tokens(cx, &fn_info.input_types);
+    let input_idents = comma_separated_tokens(cx, &fn_info.input_idents);
+    let output_type = &fn_info.output_type;
+
+    let dispatch_static = quote_item!(cx,
+        #[allow(non_upper_case_globals)]
+        static $dispatch_ident: ::hotswap_runtime::Shared<fn($input_types) -> $output_type> =
+            ::hotswap_runtime::Shared::new($dispatch_name);
+    ).unwrap();
+
+    let mut forward = item.clone();
+    forward.attrs.retain(|attr| !attr.check_name("hotswap"));
+    if let ItemKind::Fn(_, _, _, ref mut block) = forward.node {
+        mem::replace(block, P(quote_block!(cx, {
+            (*$dispatch_ident)($input_idents)
+        }).into_inner()));
     }
 
-    #[cfg(target_os = "windows")]
-    let dylib_name_template = crate_name() + "{}.dll";
+    vec![dispatch_static, shared_export(cx, dispatch_ident), P(forward)]
+}
 
-    #[cfg(target_os = "macos")]
-    let dylib_name_template = "lib".to_string() + &crate_name() + "{}.dylib";
+// The statics holding the addresses of the `#[hotswap(dispatch)]` functions in
+// the binary, shared with every library version.
+fn dispatch_statics(cx: &mut ExtCtxt, hotswap_fns: &[HotswapFnInfo]) -> Vec<P<Item>> {
+    hotswap_fns
+        .iter()
+        .filter(|hotswap_fn| hotswap_fn.options.dispatch)
+        .map(|hotswap_fn| {
+            let dispatch_ident = dispatch_ident(&hotswap_fn.name);
+            let input_types = comma_separated_tokens(cx, &hotswap_fn.input_types);
+            let output_type = &hotswap_fn.output_type;
+            let fn_path = cx.expr_path(cx.path_global(DUMMY_SP, hotswap_fn.path.clone()));
+
+            quote_item!(cx,
+                #[allow(non_upper_case_globals)]
+                pub static $dispatch_ident: fn($input_types) -> $output_type = $fn_path;
+            ).unwrap()
+        })
+        .collect()
+}
 
-    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
-    let dylib_name_template = "lib".to_string() + &crate_name() + "{}.so";
+pub fn dispatch_impl_ident(fn_name: &str) -> Ident {
+    prefixed_ident("_HOTSWAP_IMPL_", fn_name)
+}
 
-    let dylib_name = dylib_name_template.replace("{}", "");
+fn dispatch_ident(fn_name: &str) -> Ident {
+    prefixed_ident("_HOTSWAP_DISPATCH_", fn_name)
+}
 
-    let block = quote_expr!(cx, {
-        use ::std::{fs, mem, thread};
-        use ::std::env::current_exe;
-        use ::std::ops::Deref;
-        use ::std::sync::Arc;
+// Async functions are exported as plain functions returning a boxed future.
+pub fn boxed_future_type(cx: &mut ExtCtxt, output_type: &P<Ty>) -> P<Ty> {
+    quote_ty!(cx, ::std::boxed::PinBox<::std::future::Future<Output = $output_type> + Send>)
+}
 
-        use ::hotswap_runtime::libloading::Library;
-        use ::hotswap_runtime::parking_lot::Mutex;
-        use ::hotswap_runtime::RefManager;
+pub fn async_lib_body(cx: &mut ExtCtxt, block: &P<Block>) -> P<Block> {
+    P(quote_block!(cx, {
+        ::std::boxed::PinBox::new(async move $block)
+    }).into_inner())
+}
 
-        let exe = current_exe().expect("Couldn't find current executable name");
-        let dir = exe.parent().expect("Couldn't find executable path");
+pub fn hook_body(cx: &mut ExtCtxt, block: &P<Block>, output_type: &P<Ty>) -> P<Block> {
+    P(quote_block!(cx, {
+        ::hotswap_runtime::HookResult::into_hook_result((|| -> $output_type $block)())
+    }).into_inner())
+}
 
-        let tmp_path = dir.join("hotswap-dylib");
-        let dylib_file = dir.join($dylib_name);
-        let dylib_move = dylib_file.clone();
+pub fn state_export(
+    cx: &mut ExtCtxt,
+    static_ident: Ident,
+    ty: &P<Ty>,
+    serialize: bool,
+) -> P<Item> {
+    let export_ident = prefixed_ident("_HOTSWAP_STATE_", &format!("{}", static_ident.name));
+    let type_name = pprust::ty_to_string(ty);
+    let constructor = if serialize {
+        quote_expr!(cx, ::hotswap_runtime::StateInfo::serialized)
+    } else {
+        quote_expr!(cx, ::hotswap_runtime::StateInfo::raw)
+    };
+
+    // Taking the reference is unsafe for `static mut`s only.
+    let state_info = quote_expr!(cx, $constructor(unsafe { &$static_ident }, $type_name));
+
+    // A value moved as is may point into the version it is moved out of.
+    let state_info = if !serialize && static_escapes_library(ty) {
+        quote_expr!(cx, $state_info.pins_library())
+    } else {
+        state_info
+    };
 
-        if !dylib_file.exists() {
-            panic!(
-                "Couldn't find file {} - did you add a `[lib]` section to your Cargo.toml?",
-                dylib_file.to_string_lossy()
-            )
+    quote_item!(cx,
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        #[allow(unused_unsafe)]
+        pub fn $export_ident() -> ::hotswap_runtime::StateInfo {
+            $state_info
         }
+    ).unwrap()
+}
 
-        let mut last_modified = fs::metadata(&dylib_file).expect(
-            &format!(
-                "Couldn't read metadata for {}",
-                dylib_file.to_string_lossy()
-            )
-        ).modified().unwrap();
-
-        // Keep a list of all the old libs so we can refcount and drop as needed.
-        let old_libs: Arc<Mutex<Vec<RefManager>>> = Arc::new(Mutex::new(Vec::new()));
-        let old_libs_move = old_libs.clone();
-
-        let mut current_lib: Option<RefManager> = None;
-
-        let mut reload_dylib = move |dylib_num| {
-            // Windows locks the dynamic library once it is loaded, so
-            // I'm creating a copy for now.
-            let copy_name = format!($dylib_name_template, dylib_num);
-
-            let mut dylib_copy = tmp_path.clone();
-            fs::create_dir_all(&tmp_path).expect(
-                "Couldn't create temp folder for the new dynamic library"
-            );
-
-            dylib_copy.push(&copy_name);
-            fs::copy(&dylib_move, &dylib_copy).expect(
-                "Couldn't copy the dynamic library, maybe the destination has too-restrictive \
-                 permissions"
-            );
-
-            // macOS tries to be smart and prevent dylib reloading when the lib being loaded
-            // shares the same id as a previous loaded library, as a workaround we change the
-            // id of newly build libraries right after copying.
-            #[cfg(target_os="macos")]
-            {
-                use std::process::{Stdio, Command};
-
-                Command::new("install_name_tool")
-                    .args(&["-id", &copy_name, dylib_copy.to_str().unwrap()])
-                    .stderr(Stdio::null())
-                    .output()
-                    .expect("Error when trying to run `install_name_tool`, hotswap will not \
-                             work properly in  macOS systems in which it is not available.");
-            }
-
-            let lib = Library::new(dylib_copy.to_string_lossy().as_ref())
-                .expect("Failed to load library");
-
-            // Inline the function reference updaters.
-            $ref_updaters
-
-            // This should happen after the ref_updaters run, otherwise
-            // references to the previous library functions will be added
-            // to this RefManager.
-            let new_lib = Some(RefManager::new(lib));
-            let old_lib = mem::replace(&mut current_lib, new_lib);
-
-            if let Some(lib) = old_lib {
-                old_libs.lock().push(lib);
-            }
-        };
-
-        reload_dylib(0);
+pub fn shared_export(cx: &mut ExtCtxt, static_ident: Ident) -> P<Item> {
+    let export_ident = prefixed_ident("_HOTSWAP_SHARED_", &format!("{}", static_ident.name));
 
-        thread::spawn(move || {
-            let mut dylib_num = 1;
+    quote_item!(cx,
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub fn $export_ident(ptr: *const u8) {
+            $static_ident.set(ptr)
+        }
+    ).unwrap()
+}
 
-            loop {
-                thread::sleep(std::time::Duration::from_millis(5000));
+// The manifest entries pointing to the shared statics, which may be in any module.
+fn shared_statics(cx: &mut ExtCtxt, shared: &[(String, Vec<Ident>)]) -> Vec<TokenTree> {
+    let entries: Vec<_> = shared
+        .iter()
+        .map(|&(ref name, ref path)| {
+            let static_path = cx.expr_path(cx.path_global(DUMMY_SP, path.clone()));
+
+            quote_expr!(cx, ::hotswap_runtime::SharedStatic {
+                name: $name,
+                value: &$static_path,
+            })
+        })
+        .collect();
+
+    comma_separated_tokens(cx, &entries)
+}
 
-                // Check if any of the currently loaded libraries can
-                // be dropped, if so, drop them.
-                {
-                    let mut old_libs_move = old_libs_move.lock();
-                    for i in (0..old_libs_move.len()).rev() {
-                        if old_libs_move[i].should_drop() {
-                            old_libs_move.remove(i);
-                        }
-                    }
-                }
+// Lets the runtime tell the library copy of the runtime which version it is, and
+// how to find the version the calls on a thread would run.
+pub fn runtime_init(cx: &mut ExtCtxt) -> P<Item> {
+    quote_item!(cx,
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub fn _HOTSWAP_INIT(generation: usize, resolved_generation: fn() -> usize) {
+            ::hotswap_runtime::init_library(generation, resolved_generation)
+        }
+    ).unwrap()
+}
 
-                // TODO: use some filesystem notification crate
-                // so it reloads as soon as the file changes.
-                let modified = match fs::metadata(&dylib_file) {
-                    Ok(metadata) => metadata.modified().expect(
-                        "Couldn't get the metadata's modified time on this platform, hot reloading \
-                         will be horribly inefficient. If you want to use hot-reloading anyway, \
-                         file a PR to do something sensible on this platform."
-                    ),
-                    _ => continue,
-                };
-
-                if modified > last_modified {
-                    last_modified = modified;
-                    reload_dylib(dylib_num);
-                    dylib_num += 1;
-                }
-            }
-        });
-    }).into_inner();
+pub fn macro_expansion(cx: &mut ExtCtxt, config: P<Expr>) -> P<Expr> {
+    quote_expr!(cx, {
+        let config: ::hotswap_runtime::Config = $config;
+        ::hotswap_runtime::start(&::_HOTSWAP_RUNTIME::MANIFEST, config)
+    })
+}
 
-    P(block)
+fn pointer_output_type(cx: &mut ExtCtxt, fn_info: &HotswapFnInfo) -> P<Ty> {
+    if fn_info.is_async {
+        boxed_future_type(cx, &fn_info.output_type)
+    } else {
+        fn_info.output_type.clone()
+    }
 }
 
 fn pointer_ident(fn_name: &str) -> Ident {
-    Ident::with_empty_ctxt(Name::intern(&("_HOTSWAP_".to_string() + fn_name)))
+    prefixed_ident("_HOTSWAP_", fn_name)
+}
+
+fn prefixed_ident(prefix: &str, name: &str) -> Ident {
+    Ident::with_empty_ctxt(Name::intern(&(prefix.to_string() + name)))
 }
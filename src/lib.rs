@@ -7,12 +7,13 @@ extern crate syntax;
 use rustc_plugin::registry::Registry;
 
 use rustc_target::spec::abi::Abi;
-use syntax::ast::{Attribute, Ident, Item, ItemKind, MetaItem, Mod, Name, Ty, VisibilityKind};
+use syntax::ast::{Attribute, FunctionRetTy, Ident, IsAsync, Item, ItemKind, MetaItem, Mod,
+                  Mutability, Name, Ty, VisibilityKind};
 use syntax::attr;
 use syntax::codemap::Span;
 use syntax::ext::base::SyntaxExtension::{MultiModifier, NormalTT};
-use syntax::ext::base::{Annotatable, ExtCtxt, MacEager, MacResult, MultiItemModifier,
-                        TTMacroExpander};
+use syntax::ext::base::{Annotatable, DummyResult, ExtCtxt, MacEager, MacResult,
+                        MultiItemModifier, TTMacroExpander};
 use syntax::feature_gate::AttributeType;
 use syntax::ptr::P;
 use syntax::tokenstream::TokenStream;
@@ -26,7 +27,9 @@ use std::mem;
 mod codegen;
 mod util;
 
-use util::{mod_walk, rustc::*, syntax::get_fn_info};
+use util::{mod_flat_walk, rustc::*,
+           syntax::{copied_statics, escapes_library, get_fn_info, return_type, static_uses,
+                    unclassified_pointers, CopiedStatic}};
 
 #[plugin_registrar]
 pub fn plugin_registrar(reg: &mut Registry) {
@@ -64,17 +67,69 @@ pub fn plugin_registrar(reg: &mut Registry) {
     // This macro is used only as a tag so the hotswap header can find out
     // which functions should be hotswapped.
     reg.register_attribute("hotswap".to_string(), AttributeType::Whitelisted);
+
+    // Tags for the functions the runtime calls when a library version is about
+    // to become current, and when it is about to be replaced.
+    reg.register_attribute("hotswap_on_load".to_string(), AttributeType::Whitelisted);
+    reg.register_attribute("hotswap_on_unload".to_string(), AttributeType::Whitelisted);
+
+    // Tag for the statics whose value is handed to the new library version.
+    reg.register_attribute("hotswap_state".to_string(), AttributeType::Whitelisted);
+
+    // Tag for the statics the library uses from the binary instead of its own copy.
+    reg.register_attribute("hotswap_shared".to_string(), AttributeType::Whitelisted);
+
+    // Silences the warnings about statics used by hotswapped functions, on either
+    // the function or the static.
+    reg.register_attribute("hotswap_allow_statics".to_string(), AttributeType::Whitelisted);
+
+    // Silences the warnings about returned pointers that may be trait objects.
+    reg.register_attribute("hotswap_allow_pointers".to_string(), AttributeType::Whitelisted);
 }
 
 pub struct HotswapFnInfo {
     name: String,
+    // The path of the function from the crate root, including its name.
+    path: Vec<Ident>,
     input_types: Vec<Ty>,
     input_idents: Vec<Ident>,
     output_type: P<Ty>,
+    is_async: bool,
+    // Whether the returned values can outlive the library they came from.
+    pins_library: bool,
+    options: HotswapOptions,
+}
+
+// The options given to the `hotswap` attribute.
+pub struct HotswapOptions {
+    catch_panic: Option<CatchPanic>,
+    // Whether calls from the other hotswapped functions go through the current
+    // version, instead of staying in the version the caller is from.
+    dispatch: bool,
+}
+
+// What happens after a panic is caught in a `#[hotswap(catch_panic)]` function.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CatchPanic {
+    Resume,
+    // Return `Default::default()` instead.
+    Fallback,
+    // Call the function from the previous library version.
+    Retry,
 }
 
 type HotswapFnList = Vec<HotswapFnInfo>;
 
+// The items besides the hotswapped functions the runtime looks up by name.
+#[derive(Default)]
+pub struct HotswapManifest {
+    on_load: Vec<String>,
+    on_unload: Vec<String>,
+    states: Vec<String>,
+    // The shared statics, along with the path of the module they are in.
+    shared: Vec<(String, Vec<Ident>)>,
+}
+
 struct HotswapHeaderExtension {
     fn_list: Rc<RefCell<HotswapFnList>>,
 }
@@ -101,10 +156,14 @@ impl MultiItemModifier for HotswapHeaderExtension {
                 item.node = ItemKind::Mod(match crate_type().as_ref() {
                     "bin" => {
                         let mut hotswap_fns = self.fn_list.borrow_mut();
-                        let tmp = expand_bin_mod(cx, m, &mut hotswap_fns);
-                        expand_bin_footer(cx, tmp, &mut hotswap_fns)
+                        let mut manifest = HotswapManifest::default();
+                        let tmp = expand_bin_mod(cx, m, &mut hotswap_fns, &mut manifest);
+                        expand_bin_footer(cx, tmp, &mut hotswap_fns, &manifest)
+                    }
+                    "dylib" => {
+                        let tmp = expand_lib_mod(cx, m);
+                        expand_lib_footer(cx, tmp)
                     }
-                    "dylib" => expand_lib_mod(cx, m),
                     _ => unimplemented!(),
                 });
 
@@ -126,7 +185,7 @@ impl MultiItemModifier for HotswapHeaderExtension {
 }
 
 impl TTMacroExpander for HotswapMacroExtension {
-    fn expand(&self, cx: &mut ExtCtxt, _: Span, tt: TokenStream) -> Box<MacResult> {
+    fn expand(&self, cx: &mut ExtCtxt, sp: Span, tt: TokenStream) -> Box<MacResult> {
         let hotswap_fns = self.fn_list.borrow();
 
         // It will be empty when there are no functions tagged as `hotswap`,
@@ -139,16 +198,25 @@ impl TTMacroExpander for HotswapMacroExtension {
             // building the lib and stopping on the bin.
             return MacEager::expr(quote_expr!(cx, {
                 &*(0 as *const usize);
+                ::hotswap_runtime::Hotswap::inactive()
             }));
         }
 
-        if !tt.is_empty() {
-            // TODO: proper warning when user doesn't leave the macro
-            // empty.
-            unimplemented!();
-        }
+        // The macro optionally takes a `hotswap_runtime::Config` expression.
+        let config = if tt.is_empty() {
+            quote_expr!(cx, ::hotswap_runtime::Config::default())
+        } else {
+            let tts = tt.trees().collect::<Vec<_>>();
+            match cx.new_parser_from_tts(&tts).parse_expr() {
+                Ok(expr) => expr,
+                Err(mut err) => {
+                    err.emit();
+                    return DummyResult::expr(sp);
+                }
+            }
+        };
 
-        MacEager::expr(codegen::macro_expansion(cx, &hotswap_fns))
+        MacEager::expr(codegen::macro_expansion(cx, config))
     }
 }
 
@@ -160,54 +228,309 @@ fn expand_lib_attrs(cx: &mut ExtCtxt, mut attrs: Vec<Attribute>) -> Vec<Attribut
 }
 
 fn expand_lib_mod(cx: &mut ExtCtxt, m: Mod) -> Mod {
-    mod_walk(m, &mut |item| {
+    mod_flat_walk(m, &mut |item, _| {
         if attr::contains_name(&item.attrs, "hotswap") {
             match item.node {
-                ItemKind::Fn(..) => return expand_lib_fn(cx, item),
-                // TODO: write proper warning.
-                _ => println!("warning: hotswap only works on functions"),
+                ItemKind::Fn(..) => expand_lib_fn(cx, item),
+                _ => {
+                    cx.span_warn(item.span, "hotswap only works on functions");
+                    vec![item]
+                }
             }
-            expand_lib_fn(cx, item)
+        } else if is_hook(&item) {
+            vec![expand_lib_hook(cx, item)]
+        } else if attr::contains_name(&item.attrs, "hotswap_state") {
+            expand_lib_state(cx, item)
+        } else if attr::contains_name(&item.attrs, "hotswap_shared") {
+            expand_lib_shared(cx, item)
         } else {
-            item
+            vec![item]
         }
     })
 }
 
-fn expand_lib_fn(cx: &mut ExtCtxt, mut item: Item) -> Item {
-    if let ItemKind::Fn(_, ref mut header, _, _) = item.node {
-        // Make lib functions extern and no mangle so they can
-        // be imported from the runtime.
+// Exports a function describing the state static next to it, so the runtime
+// can find it in both the old and the new library.
+fn expand_lib_state(cx: &mut ExtCtxt, item: Item) -> Vec<Item> {
+    let state_export = match item.node {
+        ItemKind::Static(ref ty, mutability, _) => {
+            let serialize = attr::find_by_name(&item.attrs, "hotswap_state")
+                .and_then(|attr| attr.meta_item_list())
+                .map_or(false, |list| list.iter().any(|meta| meta.check_name("serialize")));
+
+            // Statics without interior mutability may be in read-only memory, and
+            // the syntax doesn't tell which ones have it.
+            if !serialize && mutability == Mutability::Immutable {
+                cx.struct_span_err(item.span, "hotswap_state moves the value as is")
+                    .note("only `static mut`s are sure to be writable")
+                    .help("make it a `static mut`, or use `#[hotswap_state(serialize)]`")
+                    .emit();
+                return vec![item];
+            }
+
+            codegen::state_export(cx, item.ident, ty, serialize)
+        }
+        _ => {
+            cx.span_err(item.span, "hotswap_state only works on statics");
+            return vec![item];
+        }
+    };
+
+    vec![item, state_export.into_inner()]
+}
+
+// The library's copy of a shared static is replaced by a pointer to the one in
+// the binary, which the runtime sets through an exported function before any
+// library code runs.
+fn expand_lib_shared(cx: &mut ExtCtxt, mut item: Item) -> Vec<Item> {
+    let span = item.span;
+    let name = format!("{}", item.ident.name);
+
+    let shared_export = match item.node {
+        ItemKind::Static(ref mut ty, mutability, ref mut expr) => {
+            if mutability == Mutability::Mutable {
+                cx.span_err(span, "hotswap_shared doesn't work on `static mut`s");
+                return vec![item];
+            }
+
+            mem::replace(ty, quote_ty!(cx, ::hotswap_runtime::Shared<$ty>));
+            mem::replace(expr, quote_expr!(cx, ::hotswap_runtime::Shared::new($name)));
+
+            codegen::shared_export(cx, item.ident)
+        }
+        _ => {
+            cx.span_err(span, "hotswap_shared only works on statics");
+            return vec![item];
+        }
+    };
+
+    vec![item, shared_export.into_inner()]
+}
+
+// The lib build needs the runtime as well, so `hotswap_start!` can return an
+// inactive handle, and the runtime macros work in both builds.
+fn expand_lib_footer(cx: &mut ExtCtxt, mut m: Mod) -> Mod {
+    m.items.insert(
+        0,
+        quote_item!(cx, #[macro_use] extern crate hotswap_runtime;).unwrap(),
+    );
+    m.items.push(codegen::runtime_init(cx));
+    m
+}
+
+fn expand_lib_fn(cx: &mut ExtCtxt, mut item: Item) -> Vec<Item> {
+    let fn_info = get_fn_info(cx, &item, &[]);
+
+    // Calls from the rest of the library go to a function taking the original
+    // name, which calls the current version through the binary.
+    let forward = if fn_info.options.dispatch {
+        let forward = codegen::dispatch_forward(cx, &item, &fn_info);
+        let export_name = &fn_info.name;
+
+        item.ident = codegen::dispatch_impl_ident(&fn_info.name);
+        item.attrs.push(quote_attr!(cx, #![export_name = $export_name]));
+
+        forward
+    } else {
+        item.attrs.push(quote_attr!(cx, #![no_mangle]));
+        Vec::new()
+    };
+
+    if let ItemKind::Fn(ref mut decl, ref mut header, _, ref mut block) = item.node {
+        // Make lib functions extern and exported under their own name so they
+        // can be imported from the runtime.
+        item.vis.node = VisibilityKind::Public;
+
+        mem::replace(&mut header.abi, Abi::Rust);
+
+        // The future returned by an async function has no nameable type, so
+        // export a plain function returning it boxed instead.
+        if header.asyncness.is_async() {
+            let output = return_type(cx, decl);
+            decl.output = FunctionRetTy::Ty(codegen::boxed_future_type(cx, &output));
+
+            mem::replace(&mut header.asyncness, IsAsync::NotAsync);
+            mem::replace(block, codegen::async_lib_body(cx, block));
+        }
+    }
+
+    let mut items = vec![item];
+    items.extend(forward.into_iter().map(|item| item.into_inner()));
+    items
+}
+
+fn is_hook(item: &Item) -> bool {
+    attr::contains_name(&item.attrs, "hotswap_on_load")
+        || attr::contains_name(&item.attrs, "hotswap_on_unload")
+}
+
+// Hooks are exported under their own name, returning the outcome as a
+// `Result<(), String>`, so a failing or panicking hook can abort the swap.
+fn expand_lib_hook(cx: &mut ExtCtxt, mut item: Item) -> Item {
+    let span = item.span;
+
+    if let ItemKind::Fn(ref mut decl, ref mut header, _, ref mut block) = item.node {
+        if !decl.inputs.is_empty() {
+            cx.span_err(span, "hotswap hooks can't take any arguments");
+        }
+
         item.attrs.push(quote_attr!(cx, #![no_mangle]));
         item.vis.node = VisibilityKind::Public;
 
         mem::replace(&mut header.abi, Abi::Rust);
+
+        let output = return_type(cx, decl);
+        decl.output = FunctionRetTy::Ty(quote_ty!(cx, ::std::result::Result<(), String>));
+        mem::replace(block, codegen::hook_body(cx, block, &output));
+    } else {
+        cx.span_err(span, "hotswap hooks must be functions");
     }
 
     item
 }
 
-fn expand_bin_mod(cx: &mut ExtCtxt, m: Mod, hotswap_fns: &mut HotswapFnList) -> Mod {
-    mod_walk(m, &mut |item: Item| {
+fn expand_bin_mod(
+    cx: &mut ExtCtxt,
+    m: Mod,
+    hotswap_fns: &mut HotswapFnList,
+    manifest: &mut HotswapManifest,
+) -> Mod {
+    let statics = copied_statics(&m);
+
+    mod_flat_walk(m, &mut |mut item: Item, path: &[Ident]| {
         if attr::contains_name(&item.attrs, "hotswap") {
             match item.node {
-                ItemKind::Fn(..) => return expand_bin_fn(cx, item, hotswap_fns),
-                // TODO: write proper warning.
-                _ => println!("warning: hotswap only works on functions"),
+                ItemKind::Fn(..) => {
+                    warn_static_uses(cx, &item, &statics);
+                    warn_unclassified_pointers(cx, &item);
+                    return vec![expand_bin_fn(cx, item, path, hotswap_fns)];
+                }
+                _ => cx.span_warn(item.span, "hotswap only works on functions"),
             }
         }
 
-        item
+        // Hooks are only called from the library, the runtime finds them by name.
+        if is_hook(&item) {
+            let name = format!("{}", item.ident.name);
+
+            if attr::contains_name(&item.attrs, "hotswap_on_load") {
+                manifest.on_load.push(name.clone());
+            }
+            if attr::contains_name(&item.attrs, "hotswap_on_unload") {
+                manifest.on_unload.push(name);
+            }
+
+            item.attrs.push(quote_attr!(cx, #[allow(dead_code)]));
+        }
+
+        if attr::contains_name(&item.attrs, "hotswap_state") {
+            manifest.states.push(format!("{}", item.ident.name));
+        }
+
+        // The binary keeps the only instance of shared statics, the library
+        // reaches it through the manifest.
+        if attr::contains_name(&item.attrs, "hotswap_shared") {
+            let mut static_path = path.to_vec();
+            static_path.push(item.ident);
+            manifest.shared.push((format!("{}", item.ident.name), static_path));
+
+            // The manifest is outside of the module the static is in.
+            item.vis.node = VisibilityKind::Public;
+            item.attrs.push(quote_attr!(cx, #[allow(dead_code)]));
+        }
+
+        vec![item]
     })
 }
 
-fn expand_bin_fn(cx: &mut ExtCtxt, mut item: Item, hotswap_fns: &mut HotswapFnList) -> Item {
-    let fn_info = get_fn_info(cx, &item);
+// Each library version starts with fresh copies of the statics, which is rarely
+// what the user expects from a static used by a hotswapped function.
+fn warn_static_uses(cx: &mut ExtCtxt, item: &Item, statics: &[CopiedStatic]) {
+    if attr::contains_name(&item.attrs, "hotswap_allow_statics") {
+        return;
+    }
+
+    if let ItemKind::Fn(_, _, _, ref block) = item.node {
+        for (span, copied) in static_uses(block, statics) {
+            let (kind, note) = if copied.thread_local {
+                (
+                    "thread-local",
+                    "every library version has its own copy of the thread-local, \
+                     starting from its initial value",
+                )
+            } else {
+                (
+                    "static",
+                    "every library version has its own copy of the static, starting from \
+                     its initial value, tag it with `#[hotswap_state]` or \
+                     `#[hotswap_shared]` to keep its value across reloads",
+                )
+            };
+
+            let message = format!(
+                "hotswapped function `{}` uses the {} `{}`",
+                item.ident.name, kind, copied.name
+            );
+
+            cx.struct_span_warn(span, &message)
+                .note(note)
+                .help("add `#[hotswap_allow_statics]` to the function or the static to allow it")
+                .emit();
+        }
+    }
+}
+
+// A returned `Box<Trait>` written without `dyn` isn't detected as a trait object,
+// so the library its vtable lives in may be unloaded while the value is alive.
+fn warn_unclassified_pointers(cx: &mut ExtCtxt, item: &Item) {
+    if attr::contains_name(&item.attrs, "hotswap_allow_pointers") {
+        return;
+    }
+
+    if let ItemKind::Fn(ref fn_decl, ..) = item.node {
+        let output_type = return_type(cx, fn_decl);
+        if escapes_library(&output_type) {
+            return;
+        }
+
+        for span in unclassified_pointers(&output_type) {
+            let message = format!(
+                "hotswapped function `{}` returns a pointer that may be a trait object",
+                item.ident.name
+            );
+
+            cx.struct_span_warn(span, &message)
+                .note(
+                    "trait objects keep the library that created them loaded, but are only \
+                     detected when written as `dyn Trait` or with bounds",
+                )
+                .help(
+                    "write `dyn` if the pointee is a trait, or add `#[hotswap_allow_pointers]` \
+                     to the function otherwise",
+                )
+                .emit();
+        }
+    }
+}
+
+fn expand_bin_fn(
+    cx: &mut ExtCtxt,
+    mut item: Item,
+    path: &[Ident],
+    hotswap_fns: &mut HotswapFnList,
+) -> Item {
+    let fn_info = get_fn_info(cx, &item, path);
 
     if let ItemKind::Fn(_, _, _, ref mut block) = item.node {
         mem::replace(block, codegen::fn_body(cx, &fn_info));
     }
 
+    // The library calls dispatched functions through the binary, which reaches
+    // them from the runtime module.
+    if fn_info.options.dispatch {
+        item.vis.node = VisibilityKind::Public;
+    }
+
     hotswap_fns.push(fn_info);
     item
 }
@@ -215,9 +538,16 @@ fn expand_bin_fn(cx: &mut ExtCtxt, mut item: Item, hotswap_fns: &mut HotswapFnLi
 // After all the functions to be hotswapped are found, we insert a custom module
 // at the end of the users main file, in which we store the external function
 // pointers during runtime.
-fn expand_bin_footer(cx: &mut ExtCtxt, mut m: Mod, hotswap_fns: &mut HotswapFnList) -> Mod {
-    m.items
-        .insert(0, quote_item!(cx, extern crate hotswap_runtime;).unwrap());
-    m.items.push(codegen::runtime_mod(cx, hotswap_fns));
+fn expand_bin_footer(
+    cx: &mut ExtCtxt,
+    mut m: Mod,
+    hotswap_fns: &mut HotswapFnList,
+    manifest: &HotswapManifest,
+) -> Mod {
+    m.items.insert(
+        0,
+        quote_item!(cx, #[macro_use] extern crate hotswap_runtime;).unwrap(),
+    );
+    m.items.push(codegen::runtime_mod(cx, hotswap_fns, manifest));
     m
 }
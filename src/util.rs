@@ -1,17 +1,32 @@
-use syntax::{ast::{Item, ItemKind, Mod, VisibilityKind},
+use syntax::{ast::{Ident, Item, ItemKind, Mod, VisibilityKind},
              ptr::P};
 
-pub fn mod_walk(mut m: Mod, item_map: &mut FnMut(Item) -> Item) -> Mod {
+// Maps every item in the module and its submodules to any number of items, along
+// with the path of the module containing it.
+pub fn mod_flat_walk(m: Mod, item_map: &mut FnMut(Item, &[Ident]) -> Vec<Item>) -> Mod {
+    mod_flat_walk_path(m, &mut Vec::new(), item_map)
+}
+
+fn mod_flat_walk_path(
+    mut m: Mod,
+    path: &mut Vec<Ident>,
+    item_map: &mut FnMut(Item, &[Ident]) -> Vec<Item>,
+) -> Mod {
     m.items = m.items
         .into_iter()
-        .map(|item| {
+        .flat_map(|item| {
             let mut item = item.into_inner();
             let mut should_map = false;
 
             item.node = match item.node {
                 ItemKind::Mod(m) => {
                     item.vis.node = VisibilityKind::Public;
-                    ItemKind::Mod(mod_walk(m, item_map))
+
+                    path.push(item.ident);
+                    let m = mod_flat_walk_path(m, path, item_map);
+                    path.pop();
+
+                    ItemKind::Mod(m)
                 }
                 _ => {
                     should_map = true;
@@ -20,39 +35,108 @@ pub fn mod_walk(mut m: Mod, item_map: &mut FnMut(Item) -> Item) -> Mod {
             };
 
             if should_map {
-                P(item_map(item))
+                item_map(item, path)
             } else {
-                P(item)
+                vec![item]
             }
         })
+        .map(P)
         .collect();
 
     m
 }
 pub mod syntax {
-    use syntax::ast::{FnDecl, FunctionRetTy, Ident, Item, ItemKind, PatKind, Ty};
-    use syntax::codemap;
+    use syntax::ast::{Block, FnDecl, FunctionRetTy, GenericArg, GenericArgs, Ident, Item,
+                      ItemKind, Lifetime, Mac, Mod, Name, NodeId, Path, PatKind, Ty, TyKind};
+    use syntax::attr;
+    use syntax::codemap::{self, Span};
     use syntax::ext::base::ExtCtxt;
     use syntax::ext::quote::rt::ToTokens;
     use syntax::parse::token;
     use syntax::ptr::P;
-    use syntax::tokenstream::TokenTree;
+    use syntax::symbol::keywords;
+    use syntax::tokenstream::{TokenStream, TokenTree};
+    use syntax::visit::{self, Visitor};
+
+    use {CatchPanic, HotswapFnInfo, HotswapOptions};
+
+    // `path` is the path of the module the function is in.
+    pub fn get_fn_info(cx: &mut ExtCtxt, item: &Item, path: &[Ident]) -> HotswapFnInfo {
+        if let ItemKind::Fn(ref fn_decl, ref header, _, _) = item.node {
+            let output_type = return_type(cx, fn_decl);
 
-    use HotswapFnInfo;
+            let mut fn_path = path.to_vec();
+            fn_path.push(item.ident);
 
-    pub fn get_fn_info(cx: &mut ExtCtxt, item: &Item) -> HotswapFnInfo {
-        if let ItemKind::Fn(ref fn_decl, _, _, _) = item.node {
             HotswapFnInfo {
                 name: ident_name(&item.ident),
+                path: fn_path,
                 input_types: arg_types(fn_decl),
                 input_idents: arg_idents(fn_decl),
-                output_type: return_type(cx, fn_decl),
+                pins_library: escapes_library(&output_type),
+                output_type: output_type,
+                is_async: header.asyncness.is_async(),
+                options: hotswap_options(cx, item),
             }
         } else {
             unreachable!();
         }
     }
 
+    // Reads the options of the `hotswap` attribute, which are `dispatch` and
+    // either `catch_panic`, `catch_panic(fallback)` or `catch_panic(retry)`.
+    fn hotswap_options(cx: &mut ExtCtxt, item: &Item) -> HotswapOptions {
+        let options = attr::find_by_name(&item.attrs, "hotswap")
+            .and_then(|attr| attr.meta_item_list())
+            .unwrap_or_default();
+        let mut catch_panic = None;
+        let mut dispatch = false;
+
+        for option in options {
+            if option.check_name("dispatch") {
+                dispatch = true;
+                continue;
+            }
+
+            if !option.check_name("catch_panic") {
+                cx.span_err(option.span, "unknown hotswap option");
+                continue;
+            }
+
+            let action = option.meta_item_list().map(|actions| match actions.len() {
+                1 if actions[0].check_name("fallback") => Some(CatchPanic::Fallback),
+                1 if actions[0].check_name("retry") => Some(CatchPanic::Retry),
+                _ => None,
+            });
+
+            catch_panic = match action {
+                None => Some(CatchPanic::Resume),
+                Some(Some(action)) => Some(action),
+                Some(None) => {
+                    cx.span_err(
+                        option.span,
+                        "expected `catch_panic(fallback)` or `catch_panic(retry)`",
+                    );
+                    None
+                }
+            };
+        }
+
+        if let ItemKind::Fn(_, ref header, _, _) = item.node {
+            if header.asyncness.is_async() && catch_panic.is_some() {
+                cx.span_err(item.span, "catch_panic doesn't work on async functions");
+            }
+            if header.asyncness.is_async() && dispatch {
+                cx.span_err(item.span, "dispatch doesn't work on async functions");
+            }
+        }
+
+        HotswapOptions {
+            catch_panic: catch_panic,
+            dispatch: dispatch,
+        }
+    }
+
     pub fn comma_separated_tokens<T: ToTokens>(cx: &mut ExtCtxt, entries: &[T]) -> Vec<TokenTree> {
         entries
             .iter()
@@ -61,6 +145,235 @@ pub mod syntax {
             .join(&TokenTree::Token(codemap::DUMMY_SP, token::Comma))
     }
 
+    // Checks if values of this type can hold code or data from the library that
+    // created them, such as closures, trait objects, function pointers and
+    // `'static` references, which may point into the library data segment.
+    // Trait objects are only detected when written with `dyn` or with bounds, as
+    // a bare `Box<Trait>` can't be told apart from a struct by its syntax, see
+    // `unclassified_pointers`.
+    pub fn escapes_library(ty: &Ty) -> bool {
+        let mut visitor = EscapeVisitor { found: false, references: false };
+        visitor.visit_ty(ty);
+        visitor.found
+    }
+
+    // Same as `escapes_library` for the type of a static, where every reference
+    // is `'static`, even when the lifetime is elided.
+    pub fn static_escapes_library(ty: &Ty) -> bool {
+        let mut visitor = EscapeVisitor { found: false, references: true };
+        visitor.visit_ty(ty);
+        visitor.found
+    }
+
+    struct EscapeVisitor {
+        found: bool,
+        references: bool,
+    }
+
+    impl<'a> Visitor<'a> for EscapeVisitor {
+        fn visit_ty(&mut self, ty: &'a Ty) {
+            match ty.node {
+                TyKind::BareFn(..) | TyKind::TraitObject(..) | TyKind::ImplTrait(..) => {
+                    self.found = true
+                }
+                TyKind::Rptr(..) if self.references => self.found = true,
+                _ => visit::walk_ty(self, ty),
+            }
+        }
+
+        // `Fn(A) -> B` sugar, written without `dyn`.
+        fn visit_generic_args(&mut self, span: Span, args: &'a GenericArgs) {
+            if let GenericArgs::Parenthesized(..) = *args {
+                self.found = true;
+            }
+
+            visit::walk_generic_args(self, span, args);
+        }
+
+        // String literals and references to statics, e.g. `&'static str` or
+        // `Cow<'static, [u8]>`.
+        fn visit_lifetime(&mut self, lifetime: &'a Lifetime) {
+            if lifetime.ident.name == keywords::StaticLifetime.name() {
+                self.found = true;
+            }
+        }
+    }
+
+    // Finds the `Box`, `Rc` and `Arc` in a type whose pointee is a plain path,
+    // which may be a struct or a trait object written without `dyn`. Generic and
+    // well known pointees are skipped.
+    pub fn unclassified_pointers(ty: &Ty) -> Vec<Span> {
+        let mut visitor = PointerVisitor { found: Vec::new() };
+        visitor.visit_ty(ty);
+        visitor.found
+    }
+
+    struct PointerVisitor {
+        found: Vec<Span>,
+    }
+
+    impl<'a> Visitor<'a> for PointerVisitor {
+        fn visit_ty(&mut self, ty: &'a Ty) {
+            if let TyKind::Path(None, ref path) = ty.node {
+                let segment = path.segments.last();
+                let pointee = segment
+                    .filter(|segment| {
+                        ["Box", "Rc", "Arc"]
+                            .iter()
+                            .any(|pointer| segment.ident.name == *pointer)
+                    })
+                    .and_then(|segment| segment.args.as_ref())
+                    .and_then(|args| match **args {
+                        GenericArgs::AngleBracketed(ref args) if args.args.len() == 1 => {
+                            match args.args[0] {
+                                GenericArg::Type(ref pointee) => Some(pointee),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    });
+
+                // Generic pointees are rarely traits, e.g. `Rc<RefCell<T>>`.
+                if let Some(pointee) = pointee {
+                    if let TyKind::Path(None, ref pointee_path) = pointee.node {
+                        let generic =
+                            pointee_path.segments.iter().any(|segment| segment.args.is_some());
+                        let known = pointee_path.segments.last().map_or(false, |segment| {
+                            KNOWN_TYPES.iter().any(|name| segment.ident.name == *name)
+                        });
+
+                        if !generic && !known {
+                            self.found.push(ty.span);
+                        }
+                    }
+                }
+            }
+
+            visit::walk_ty(self, ty);
+        }
+    }
+
+    // Primitives and the std types commonly put behind pointers.
+    const KNOWN_TYPES: &[&str] = &[
+        "bool", "char", "str", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32",
+        "u64", "u128", "usize", "f32", "f64", "String", "CStr", "CString", "OsStr", "OsString",
+        "Path", "PathBuf",
+    ];
+
+    // A static or thread-local of which every library version has its own copy.
+    pub struct CopiedStatic {
+        pub name: Name,
+        pub thread_local: bool,
+    }
+
+    // Finds the statics and `thread_local!`s defined anywhere in the module, except
+    // for the ones whose value is kept across reloads, or that allow being copied.
+    pub fn copied_statics(m: &Mod) -> Vec<CopiedStatic> {
+        let mut statics = Vec::new();
+
+        for item in &m.items {
+            let allowed = attr::contains_name(&item.attrs, "hotswap_state")
+                || attr::contains_name(&item.attrs, "hotswap_shared")
+                || attr::contains_name(&item.attrs, "hotswap_allow_statics");
+
+            match item.node {
+                ItemKind::Mod(ref m) => statics.extend(copied_statics(m)),
+                ItemKind::Static(..) if !allowed => statics.push(CopiedStatic {
+                    name: item.ident.name,
+                    thread_local: false,
+                }),
+                // Macros are not expanded yet, so the names are taken from the
+                // `static NAME: T = ...` declarations in the invocation.
+                ItemKind::Mac(ref mac) if is_thread_local(mac) && !allowed => {
+                    let mut after_static = false;
+
+                    for tree in mac.node.stream().trees() {
+                        if let TokenTree::Token(_, ref tok) = tree {
+                            if let token::Ident(ident, _) = *tok {
+                                if after_static {
+                                    statics.push(CopiedStatic {
+                                        name: ident.name,
+                                        thread_local: true,
+                                    });
+                                }
+                            }
+                            after_static = tok.is_keyword(keywords::Static);
+                        } else {
+                            after_static = false;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        statics
+    }
+
+    fn is_thread_local(mac: &Mac) -> bool {
+        mac.node
+            .path
+            .segments
+            .last()
+            .map_or(false, |segment| segment.ident.name == "thread_local")
+    }
+
+    // Finds the uses of the given statics in a function body, first use of each
+    // static only. Paths aren't resolved yet, so statics are matched by name,
+    // including in macro arguments.
+    pub fn static_uses<'s>(
+        block: &Block,
+        statics: &'s [CopiedStatic],
+    ) -> Vec<(Span, &'s CopiedStatic)> {
+        let mut visitor = StaticVisitor {
+            statics: statics,
+            uses: Vec::new(),
+        };
+        visitor.visit_block(block);
+        visitor.uses
+    }
+
+    struct StaticVisitor<'s> {
+        statics: &'s [CopiedStatic],
+        uses: Vec<(Span, &'s CopiedStatic)>,
+    }
+
+    impl<'s> StaticVisitor<'s> {
+        fn found(&mut self, span: Span, name: Name) {
+            let used = self.uses.iter().any(|&(_, used)| used.name == name);
+
+            if let Some(copied) = self.statics.iter().find(|copied| copied.name == name) {
+                if !used {
+                    self.uses.push((span, copied));
+                }
+            }
+        }
+
+        fn visit_tokens(&mut self, stream: TokenStream) {
+            for tree in stream.trees() {
+                match tree {
+                    TokenTree::Token(span, token::Ident(ident, _)) => self.found(span, ident.name),
+                    TokenTree::Token(..) => {}
+                    TokenTree::Delimited(_, delimited) => self.visit_tokens(delimited.stream()),
+                }
+            }
+        }
+    }
+
+    impl<'a, 's> Visitor<'a> for StaticVisitor<'s> {
+        fn visit_path(&mut self, path: &'a Path, _: NodeId) {
+            if let Some(segment) = path.segments.last() {
+                self.found(path.span, segment.ident.name);
+            }
+
+            visit::walk_path(self, path);
+        }
+
+        fn visit_mac(&mut self, mac: &'a Mac) {
+            self.visit_tokens(mac.node.stream());
+        }
+    }
+
     fn ident_name(ident: &Ident) -> String {
         format!("{}", ident.name)
     }
@@ -87,7 +400,7 @@ pub mod syntax {
         fn_decl.inputs.iter().map(|arg| (*arg.ty).clone()).collect()
     }
 
-    fn return_type(cx: &mut ExtCtxt, fn_decl: &FnDecl) -> P<Ty> {
+    pub fn return_type(cx: &mut ExtCtxt, fn_decl: &FnDecl) -> P<Ty> {
         match fn_decl.output {
             FunctionRetTy::Ty(ref ty) => ty.clone(),
             _ => quote_ty!(cx, ()),
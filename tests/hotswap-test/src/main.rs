@@ -3,6 +3,9 @@
 #![hotswap_header]
 
 use std::io;
+use std::panic;
+
+use hotswap_runtime::ReloadEvent;
 
 #[hotswap]
 fn test() -> String {
@@ -14,9 +17,68 @@ fn test() -> String {
     return result;
 }
 
+fn version() -> &'static str {
+    #[cfg(not(feature="hotswap_toggle"))]
+    return "first";
+    #[cfg(feature="hotswap_toggle")]
+    return "second";
+}
+
+// The library is reloaded by `wait` before the recursive call, which stays in
+// the same version.
+#[hotswap]
+fn recurse(depth: u32, wait: fn()) -> String {
+    if depth == 0 {
+        return version().to_string();
+    }
+
+    wait();
+    format!("{} {}", version(), recurse(depth - 1, wait))
+}
+
+// Same as `recurse`, but the recursive call goes to the current version.
+#[hotswap(dispatch)]
+fn recurse_dispatch(depth: u32, wait: fn()) -> String {
+    if depth == 0 {
+        return version().to_string();
+    }
+
+    wait();
+    format!("{} {}", version(), recurse_dispatch(depth - 1, wait))
+}
+
+// The panic is caught and reported before unwinding into the caller.
+#[hotswap(catch_panic)]
+fn panic_resume() -> String {
+    panic!("resumed from {}", version());
+}
+
+#[hotswap(catch_panic(fallback))]
+fn panic_fallback() -> String {
+    panic!("replaced by the default value");
+}
+
+// Only the second version panics, and the call is retried in the first one,
+// with a copy of the original argument.
+#[hotswap(catch_panic(retry))]
+fn panic_retry(prefix: String) -> String {
+    if version() == "second" {
+        panic!("retried in the first version");
+    }
+
+    format!("{} {}", prefix, version())
+}
+
+fn wait_for_reload() {
+    println!("reload");
+
+    let mut buffer = String::new();
+    io::stdin().read_line(&mut buffer).unwrap();
+}
 
 fn main() {
-    unsafe { hotswap_start!() }
+    let _hotswap = unsafe { hotswap_start!() };
+    let events = hotswap_runtime::subscribe();
 
     println!("ready");
 
@@ -25,6 +87,23 @@ fn main() {
     io::stdin().read_line(&mut buffer).unwrap();
     println!("{}", test());
 
+    println!("{}", panic::catch_unwind(panic_resume).is_err());
+    println!("{:?}", panic_fallback());
+
+    println!("{}", recurse(1, wait_for_reload));
+
     io::stdin().read_line(&mut buffer).unwrap();
     println!("{}", test());
+    println!("{}", panic_retry("retried".to_string()));
+
+    println!("{}", recurse_dispatch(1, wait_for_reload));
+
+    let panicked: Vec<_> = events
+        .try_iter()
+        .filter_map(|event| match event {
+            ReloadEvent::Panicked(report) => Some(report.function),
+            _ => None,
+        })
+        .collect();
+    println!("{}", panicked.join(" "));
 }
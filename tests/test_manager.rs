@@ -1,8 +1,37 @@
-use std::process::{Command, Stdio};
+use std::process::{Command, Stdio, ChildStdin, ChildStdout};
 use std::io::{Write, BufReader, BufRead};
 use std::thread::sleep;
 use std::time::Duration;
 
+fn read_line(stdout: &mut BufReader<ChildStdout>) -> String {
+    let mut output = String::new();
+    stdout.read_line(&mut output).unwrap();
+    println!("{}", output.trim());
+    output.trim().to_string()
+}
+
+fn build_lib(features: &[&str]) {
+    println!("building lib");
+    let mut command = Command::new("cargo");
+    command.args(&["build", "--lib"]);
+    if !features.is_empty() {
+        command.arg("--features").arg(features.join(" "));
+    }
+
+    command
+        .current_dir("tests/hotswap-test")
+        .stdout(Stdio::null())
+        .status()
+        .expect("Failed to build lib");
+
+    // Wait while hotswap reads the freshly compiled library.
+    sleep(Duration::from_millis(5000));
+}
+
+fn echo(stdin: &mut ChildStdin) {
+    stdin.write(b"echo\n").unwrap();
+}
+
 #[test]
 fn hotswap_test_crate() {
     let mut child = Command::new("cargo")
@@ -17,35 +46,37 @@ fn hotswap_test_crate() {
     let mut stdin = child.stdin.take().unwrap();
 
     println!("building bin");
-    let mut output = String::new();
-    stdout.read_line(&mut output).unwrap();
-    println!("{}", output.trim());
+    read_line(&mut stdout);
 
     println!("running test crate");
-    stdin.write(b"echo\n").unwrap();
-    output.clear();
-    stdout.read_line(&mut output).unwrap();
+    echo(&mut stdin);
+    assert!(read_line(&mut stdout) == "first");
 
-    println!("{}", output.trim());
-    assert!(output.trim() == "first");
+    // Caught panics are resumed, or replaced by the default value.
+    assert!(read_line(&mut stdout) == "true");
+    assert!(read_line(&mut stdout) == "\"\"");
 
-    println!("building lib");
-    Command::new("cargo")
-        .args(&["build", "--lib", "--features", "hotswap_toggle"])
-        .current_dir("tests/hotswap-test")
-        .stdout(Stdio::null())
-        .status()
-        .expect("Failed to build lib");
+    // Calls between hotswapped functions stay in the version of the caller,
+    // even across a reload.
+    assert!(read_line(&mut stdout) == "reload");
+    build_lib(&["hotswap_toggle"]);
+    echo(&mut stdin);
+    assert!(read_line(&mut stdout) == "first first");
 
-    // Wait while hotswap reads the freshly compiled library.
-    sleep(Duration::from_millis(5000));
+    echo(&mut stdin);
+    assert!(read_line(&mut stdout) == "second");
 
-    stdin.write(b"echo\n").unwrap();
-    output.clear();
-    stdout.read_line(&mut output).unwrap();
+    // Caught panics can also be retried in the previous version.
+    assert!(read_line(&mut stdout) == "retried first");
 
-    println!("{}", output.trim());
-    assert!(output.trim() == "second");
+    // Unless the callee is dispatched, then the call goes to the current version.
+    assert!(read_line(&mut stdout) == "reload");
+    build_lib(&[]);
+    echo(&mut stdin);
+    assert!(read_line(&mut stdout) == "second first");
+
+    // Every caught panic reached the subscribers.
+    assert!(read_line(&mut stdout) == "panic_resume panic_fallback panic_retry");
 
     child.wait().unwrap();
 }